@@ -20,12 +20,19 @@ float sig(float x, float x0, float r) {
     return 1.0/(1.0 + exp(-(rs*(x-x0))));
 }
 
+vec2 rotate(vec2 p, float a) {
+    return mat2(cos(a), sin(a), -sin(a), cos(a)) * p;
+}
+
+{{@defs}}
+
 void main() {
     float u = uv.x;
     float v = uv.y;
     float r = sqrt(u*u + v*v);
-    float red = mult(add(sin(t),1.0),0.5);
-    float green = v;
-    float blue = u;
+    {{@stmts}}
+    float red = {{red}};
+    float green = {{green}};
+    float blue = {{blue}};
     color = vec4(red, green, blue, 1.0);
 }
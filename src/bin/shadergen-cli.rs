@@ -0,0 +1,233 @@
+//! Batch generation of fragment shaders (and optionally CPU rendered previews) from a grammar,
+//! without starting the GUI.
+
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use shadergen::{
+    codegen::CodegenOpts,
+    functions::FunctionRegistry,
+    headless::{gen_slots, render_channels, unused_slots, Slot},
+    json::to_json,
+    parser::parse_rewrite_rules_with,
+    shader::DEFAULT_FRAG,
+};
+
+static USAGE: &str = "\
+Usage: shadergen-cli <grammar file> [options]
+
+Generates fragment shaders, seeds give the same functions as in the app.
+
+Options:
+  --frag <file>        shader template with {{red}}, {{green}} and {{blue}} placeholders to fill
+                       in (default: built in template)
+  --seed <n>           seed to generate from (default: 0)
+  --seeds <a>..<b>     generate from every seed in a range (end exclusive)
+  --max-depth <n>      maximum depth of generated functions (default: 10)
+  --slot <name>=<rule>[:<depth>]
+                       also generate {{name}} from a rule, on top of the channels (or colour),
+                       can be given more than once
+  --out-dir <dir>      write shader_<seed>.glsl files to dir instead of printing to stdout
+  --png                also render shader_<seed>.png on the CPU (requires --out-dir)
+  --json               also write the generated functions to shader_<seed>.json (requires
+                       --out-dir)
+  --no-simplify        keep constant subexpressions rather than folding them
+  --no-cse             repeat subexpressions rather than declaring them once as temporaries
+  --t <t>              time to render the png at (default: 0)
+  --size <w>x<h>       size of rendered png (default: 640x360)
+  -h, --help           print this message";
+
+struct Args {
+    grammar: PathBuf,
+    frag: Option<PathBuf>,
+    seeds: std::ops::RangeInclusive<u64>,
+    max_depth: usize,
+    /// besides the default slots, their max depth to be filled in
+    slots: Vec<(String, String, Option<usize>)>,
+    out_dir: Option<PathBuf>,
+    png: bool,
+    json: bool,
+    simplify: bool,
+    codegen_opts: CodegenOpts,
+    t: f32,
+    size: (u32, u32),
+}
+
+fn parse_num<T: std::str::FromStr>(flag: &str, val: &str) -> Result<T, String> {
+    val.parse()
+        .map_err(|_| format!("Could not parse value \"{val}\" given to {flag}."))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut grammar = None;
+    let mut args = Args {
+        grammar: PathBuf::new(),
+        frag: None,
+        seeds: 0..=0,
+        max_depth: 10,
+        slots: vec![],
+        out_dir: None,
+        png: false,
+        json: false,
+        simplify: true,
+        codegen_opts: CodegenOpts::default(),
+        t: 0.0,
+        size: (640, 360),
+    };
+    let mut it = std::env::args().skip(1);
+    while let Some(arg) = it.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        if arg == "--png" {
+            args.png = true;
+            continue;
+        }
+        if arg == "--json" {
+            args.json = true;
+            continue;
+        }
+        if arg == "--no-simplify" {
+            args.simplify = false;
+            continue;
+        }
+        if arg == "--no-cse" {
+            args.codegen_opts.cse = false;
+            continue;
+        }
+        if !arg.starts_with("--") {
+            match grammar {
+                None => grammar = Some(PathBuf::from(arg)),
+                Some(_) => return Err(format!("Unexpected argument \"{arg}\".")),
+            }
+            continue;
+        }
+        let val = it
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}."))?;
+        match arg.as_str() {
+            "--frag" => args.frag = Some(PathBuf::from(val)),
+            "--seed" => {
+                let seed = parse_num(&arg, &val)?;
+                args.seeds = seed..=seed;
+            }
+            "--seeds" => {
+                let (a, b) = val
+                    .split_once("..")
+                    .ok_or_else(|| format!("Expected a range like 0..10 for {arg}."))?;
+                let (a, b): (u64, u64) = (parse_num(&arg, a)?, parse_num(&arg, b)?);
+                if b <= a {
+                    return Err(format!("The range given to {arg} is empty."));
+                }
+                args.seeds = a..=b - 1;
+            }
+            "--max-depth" => args.max_depth = parse_num(&arg, &val)?,
+            "--slot" => {
+                let (name, rule) = val
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected a slot like alpha=A for {arg}."))?;
+                let (rule, depth) = match rule.split_once(':') {
+                    Some((rule, depth)) => (rule, Some(parse_num(&arg, depth)?)),
+                    None => (rule, None),
+                };
+                args.slots.push((name.to_string(), rule.to_string(), depth));
+            }
+            "--out-dir" => args.out_dir = Some(PathBuf::from(val)),
+            "--t" => args.t = parse_num(&arg, &val)?,
+            "--size" => {
+                let (w, h) = val
+                    .split_once('x')
+                    .ok_or_else(|| format!("Expected a size like 640x360 for {arg}."))?;
+                args.size = (parse_num(&arg, w)?, parse_num(&arg, h)?);
+            }
+            _ => return Err(format!("Unknown option {arg}.\n\n{USAGE}")),
+        }
+    }
+    args.grammar = grammar.ok_or_else(|| USAGE.to_string())?;
+    if args.png && args.out_dir.is_none() {
+        return Err("--png requires --out-dir.".to_string());
+    }
+    if args.json && args.out_dir.is_none() {
+        return Err("--json requires --out-dir.".to_string());
+    }
+    Ok(args)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let read = |path: &PathBuf| {
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))
+    };
+    let grammar = read(&args.grammar)?;
+    let frag = match &args.frag {
+        Some(path) => read(path)?,
+        None => DEFAULT_FRAG.to_string(),
+    };
+    // NOTE: grammars may call any function the shader defines
+    let rr = parse_rewrite_rules_with(&grammar, FunctionRegistry::from_shader(&frag))
+        .map_err(|e| format!("Parse error {}", e.report(&grammar)))?;
+    for w in rr.warnings.iter() {
+        eprintln!("Grammar warning {}", w.report(&grammar));
+    }
+    let mut slots = Slot::defaults(&rr, args.max_depth);
+    for (name, entry, max_depth) in args.slots.iter() {
+        let slot = Slot {
+            name: name.clone(),
+            entry: entry.clone(),
+            max_depth: max_depth.unwrap_or(args.max_depth),
+        };
+        slot.check(&rr)
+            .map_err(|e| format!("Bad slot {name}: {e:?}"))?;
+        slots.push(slot);
+    }
+    let names: Vec<_> = slots.iter().map(|slot| slot.name.as_str()).collect();
+    for name in unused_slots(&frag, &names) {
+        eprintln!("Warning: the template has no {{{{{name}}}}} placeholder for slot {name}");
+    }
+    if let Some(dir) = &args.out_dir {
+        fs::create_dir_all(dir).map_err(|e| format!("Could not create {}: {e}", dir.display()))?;
+    }
+
+    for seed in args.seeds.clone() {
+        let mut generated = gen_slots(&rr, seed, args.max_depth, &slots);
+        if args.simplify {
+            generated.simplify(&rr.funcs);
+        }
+        let code = generated
+            .render(&frag, &rr.funcs, args.codegen_opts)
+            .map_err(|e| format!("Template error {e:?}"))?;
+        let code = format!("// seed {seed}, max depth {}\n{code}\n", args.max_depth);
+        match &args.out_dir {
+            Some(dir) => {
+                let path = dir.join(format!("shader_{seed}.glsl"));
+                fs::write(&path, code)
+                    .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+                if args.json {
+                    let path = dir.join(format!("shader_{seed}.json"));
+                    let json = to_json(&generated)
+                        .map_err(|e| format!("Could not serialise functions: {e}"))?;
+                    fs::write(&path, json)
+                        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+                }
+                if args.png {
+                    let (w, h) = args.size;
+                    let path = dir.join(format!("shader_{seed}.png"));
+                    render_channels(&rr.funcs, &generated, args.t, w, h)
+                        .save_png(&path)
+                        .map_err(|e| format!("Could not write {}: {e:?}", path.display()))?;
+                }
+            }
+            None => print!("{code}"),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match parse_args().and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
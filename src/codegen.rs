@@ -0,0 +1,357 @@
+//! GLSL for generated functions, along with the helper functions some expressions need.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    functions::FunctionRegistry,
+    parser::{Expression, Term, WARP},
+    types::Type,
+};
+
+/// prefix of generated helper names, which grammars cannot call
+pub const HELPER_PREFIX: &str = "_warp";
+/// prefix of generated temporaries, see `Codegen::share_repeated`
+pub const TEMP_PREFIX: &str = "_tmp";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CodegenOpts {
+    /// declare repeated subexpressions once, as temporaries
+    pub cse: bool,
+}
+
+impl Default for CodegenOpts {
+    fn default() -> Self {
+        Self { cse: true }
+    }
+}
+
+/// Turns expressions into GLSL, collecting helpers to define before `main`. Expressions sharing
+/// a `Codegen` get helpers with distinct names.
+#[derive(Default)]
+pub struct Codegen<'a> {
+    /// full GLSL definitions, each only calling ones before it
+    pub helpers: Vec<String>,
+    /// to put in `main` before the colour is computed, e.g. bindings
+    pub stmts: Vec<String>,
+    shared: Option<Shared<'a>>,
+    /// how many warp bodies deep the expression being emitted is
+    warp_depth: usize,
+}
+
+/// subexpressions to declare as temporaries
+struct Shared<'a> {
+    /// needed for the types of temporaries
+    funcs: &'a FunctionRegistry,
+    nodes: Interner,
+    /// ids of the subexpressions to declare as temporaries
+    hoisted: HashSet<usize>,
+    /// names of the temporaries declared so far, by id
+    temps: HashMap<usize, String>,
+}
+
+/// bindings an expression uses, in order of first use
+fn vars<'a>(expr: &'a Expression, found: &mut Vec<(&'a str, Type)>) {
+    match expr {
+        Expression::Terminal(Term::Var { name, ty }) => {
+            if !found.iter().any(|(var, _)| var == name) {
+                found.push((name, *ty));
+            }
+        }
+        _ => expr.args().iter().for_each(|arg| vars(arg, found)),
+    }
+}
+
+/// a node with its args given by their ids, see `Interner`
+#[derive(PartialEq, Eq, Hash)]
+enum NodeKey {
+    /// by its string
+    Leaf(String),
+    Func(String, Vec<usize>),
+    /// the coordinates followed by the body
+    Warp(Vec<usize>),
+}
+
+struct Node {
+    args: Vec<usize>,
+    kind: NodeKind,
+    /// length of the node as a string, without temporaries
+    len: usize,
+}
+
+#[derive(PartialEq)]
+enum NodeKind {
+    Leaf,
+    Func,
+    Warp,
+}
+
+/// the ids of an expression's nodes, in the shape of the expression
+struct Ids {
+    id: usize,
+    args: Vec<Ids>,
+}
+
+/// Gives structurally equal subexpressions the same id (hash-consing), working bottom-up so that
+/// each node is visited once. Args always get lower ids than the nodes using them.
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<NodeKey, usize>,
+    /// by id
+    nodes: Vec<Node>,
+}
+
+impl Interner {
+    fn intern(&mut self, expr: &Expression) -> Ids {
+        let args: Vec<_> = expr.args().iter().map(|arg| self.intern(arg)).collect();
+        let arg_ids: Vec<_> = args.iter().map(|arg| arg.id).collect();
+        // `name(a,b)`
+        let call_len = |name: &str| {
+            let args_len: usize = arg_ids.iter().map(|id| self.nodes[*id].len).sum();
+            name.len() + 2 + args_len + arg_ids.len().saturating_sub(1)
+        };
+        let (key, kind, len) = match expr {
+            Expression::Func { ident, .. } => (
+                NodeKey::Func(ident.clone(), arg_ids.clone()),
+                NodeKind::Func,
+                call_len(ident),
+            ),
+            Expression::Warp { .. } => (
+                NodeKey::Warp(arg_ids.clone()),
+                NodeKind::Warp,
+                call_len(WARP),
+            ),
+            Expression::Terminal(..) | Expression::ToBeReplaced { .. } => {
+                let leaf = expr.as_string();
+                let len = leaf.len();
+                (NodeKey::Leaf(leaf), NodeKind::Leaf, len)
+            }
+        };
+        let next = self.nodes.len();
+        let id = *self.ids.entry(key).or_insert(next);
+        if id == next {
+            self.nodes.push(Node {
+                args: arg_ids,
+                kind,
+                len,
+            });
+        }
+        Ids { id, args }
+    }
+}
+
+/// Whether declaring a subexpression of `len` chars used `uses` times as the temporary numbered
+/// `n` takes fewer chars than repeating it, counting its declaration (`    float _tmp0 = ...;`).
+fn worth_sharing(len: usize, uses: usize, n: usize) -> bool {
+    let name = TEMP_PREFIX.len() + n.to_string().len();
+    let decl = "    float  = ;\n".len() + name + len;
+    uses > 1 && uses * len > uses * name + decl
+}
+
+/// the type an expression produces, float where it cannot be told
+pub fn expr_type(expr: &Expression, funcs: &FunctionRegistry) -> Type {
+    match expr {
+        Expression::Terminal(term) => term.ty(),
+        Expression::Warp { ty, .. } => *ty,
+        Expression::Func { ident, args } => {
+            let args: Vec<_> = args.iter().map(|a| expr_type(a, funcs)).collect();
+            funcs
+                .get(ident)
+                .and_then(|def| def.resolve(&args).ok())
+                .unwrap_or(Type::Float)
+        }
+        Expression::ToBeReplaced { .. } => Type::Float,
+    }
+}
+
+impl<'a> Codegen<'a> {
+    pub fn new(funcs: &'a FunctionRegistry, opts: CodegenOpts, exprs: &[&Expression]) -> Self {
+        let mut new = Self::default();
+        if opts.cse {
+            new.share_repeated(funcs, exprs);
+        }
+        new
+    }
+
+    /// Has subexpressions occurring more than once in `exprs` declared as temporaries the first
+    /// time they are emitted, where that shortens the code. Larger subexpressions go first, so
+    /// that the parts of a shared one only count as repeated if they are used elsewhere too.
+    /// Repeated warps are always shared, as each use would get a helper of its own otherwise.
+    /// Warp bodies are left as they are, as they are evaluated at other coordinates.
+    pub fn share_repeated(&mut self, funcs: &'a FunctionRegistry, exprs: &[&Expression]) {
+        let mut nodes = Interner::default();
+        let roots: Vec<_> = exprs.iter().map(|e| nodes.intern(e).id).collect();
+        // how many times each node ends up in the code, given the ones shared so far
+        let mut uses = vec![0; nodes.nodes.len()];
+        roots.iter().for_each(|root| uses[*root] += 1);
+        let mut hoisted = HashSet::new();
+        // NOTE: nodes using others have higher ids, so all their uses are known by now
+        for id in (0..uses.len()).rev() {
+            let node = &nodes.nodes[id];
+            let share = match node.kind {
+                NodeKind::Leaf => false,
+                NodeKind::Func => worth_sharing(node.len, uses[id], hoisted.len()),
+                NodeKind::Warp => uses[id] > 1,
+            };
+            if share {
+                hoisted.insert(id);
+                uses[id] = 1;
+            }
+            let args = match node.kind {
+                NodeKind::Warp => &node.args[..node.args.len() - 1],
+                _ => &node.args[..],
+            };
+            for arg in args {
+                uses[*arg] += uses[id];
+            }
+        }
+        self.shared = Some(Shared {
+            funcs,
+            nodes,
+            hoisted,
+            temps: HashMap::new(),
+        });
+    }
+
+    /// declares a binding as a local variable
+    pub fn bind(&mut self, name: &str, ty: Type, expr: &Expression) {
+        let expr = self.glsl(expr);
+        self.stmts.push(format!("{} {name} = {expr};", ty.as_str()));
+    }
+
+    pub fn glsl(&mut self, expr: &Expression) -> String {
+        let ids = self.shared.as_mut().map(|shared| shared.nodes.intern(expr));
+        self.emit(expr, ids.as_ref())
+    }
+
+    /// `ids` are those of `expr`, when sharing subexpressions
+    fn emit(&mut self, expr: &Expression, ids: Option<&Ids>) -> String {
+        let id = match (&self.shared, ids) {
+            (Some(shared), Some(ids))
+                if self.warp_depth == 0 && shared.hoisted.contains(&ids.id) =>
+            {
+                ids.id
+            }
+            _ => return self.emit_node(expr, ids),
+        };
+        if let Some(temp) = self.shared.as_ref().unwrap().temps.get(&id) {
+            return temp.clone();
+        }
+        let value = self.emit_node(expr, ids);
+        let shared = self.shared.as_mut().unwrap();
+        let temp = format!("{TEMP_PREFIX}{}", shared.temps.len());
+        let ty = expr_type(expr, shared.funcs);
+        self.stmts
+            .push(format!("{} {temp} = {value};", ty.as_str()));
+        shared.temps.insert(id, temp.clone());
+        temp
+    }
+
+    /// without looking up temporaries for `expr` itself
+    fn emit_node(&mut self, expr: &Expression, ids: Option<&Ids>) -> String {
+        let mut args = expr
+            .args()
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| (arg, ids.map(|ids| &ids.args[i])));
+        match expr {
+            Expression::Func { ident, .. } => {
+                let args: Vec<_> = args.map(|(arg, ids)| self.emit(arg, ids)).collect();
+                format!("{ident}({})", args.join(","))
+            }
+            // NOTE: GLSL has no expressions with local variables, so the body becomes a function
+            // of the warped coordinates instead (and of the bindings it uses, which are local to
+            // main)
+            Expression::Warp { ty, .. } => {
+                let (body, body_ids) = args.next_back().unwrap();
+                let coords: Vec<_> = args.map(|(arg, ids)| self.emit(arg, ids)).collect();
+                let mut call_args = vec![match coords[..] {
+                    [ref uv] => uv.clone(),
+                    _ => format!("vec2({})", coords.join(",")),
+                }];
+                let mut params = vec!["vec2 uv".to_string()];
+                let mut found = vec![];
+                vars(body, &mut found);
+                for (var, ty) in found {
+                    call_args.push(var.to_string());
+                    params.push(format!("{} {var}", ty.as_str()));
+                }
+                self.warp_depth += 1;
+                let body = self.emit(body, body_ids);
+                self.warp_depth -= 1;
+                let name = format!("{HELPER_PREFIX}{}", self.helpers.len());
+                self.helpers.push(format!(
+                    "{} {name}({}) {{\n    float u = uv.x;\n    float v = uv.y;\n    float r = sqrt(u*u + v*v);\n    return {body};\n}}",
+                    ty.as_str(),
+                    params.join(", ")
+                ));
+                format!("{name}({})", call_args.join(","))
+            }
+            Expression::Terminal(..) | Expression::ToBeReplaced { .. } => expr.as_string(),
+        }
+    }
+}
+
+#[test]
+fn warp_glsl_test() {
+    use crate::{funcgen::seed_rng, parser::parse_rewrite_rules, types::Value};
+
+    let rr = parse_rewrite_rules("C | warp(v, u, sin(warp(uv, t))) ;").unwrap();
+    seed_rng(0);
+    let func = rr.gen_fn("C", 5);
+    let funcs = &rr.funcs;
+    assert_eq!(
+        func.eval(funcs, &Default::default(), 0.1, 0.2, 0.3, 0.0),
+        Value::Float(0.3f32.sin())
+    );
+    let mut codegen = Codegen::default();
+    assert_eq!(codegen.glsl(&func), "_warp1(vec2(v,u))");
+    assert_eq!(codegen.helpers.len(), 2);
+    assert!(codegen.helpers[0].starts_with("float _warp0(vec2 uv) {"));
+    assert!(codegen.helpers[1].ends_with("return sin(_warp0(uv));\n}"));
+}
+
+#[test]
+fn let_glsl_test() {
+    use crate::parser::parse_rewrite_rules;
+
+    let rr = parse_rewrite_rules("@let a0 = A;\nC | warp(uv, add(a0, u)) ;\nA | t ;").unwrap();
+    let func = rr.gen_fn("C", 5);
+    let mut codegen = Codegen::default();
+    codegen.bind("a0", Type::Float, &rr.gen_fn("A", 5));
+    assert_eq!(codegen.glsl(&func), "_warp0(uv,a0)");
+    assert_eq!(codegen.stmts, ["float a0 = t;"]);
+    assert!(codegen.helpers[0].starts_with("float _warp0(vec2 uv, float a0) {"));
+}
+
+#[test]
+fn cse_test() {
+    use crate::parser::parse_rewrite_rules;
+
+    let x = "mult(sin(mult(u,v)),cos(add(u,v)))";
+    let src = format!("C | add({x}, {x}) ;\nD | mult({x}, warp(uv, {x})) ;");
+    let rr = parse_rewrite_rules(&src).unwrap();
+    let (c, d) = (rr.gen_fn("C", 5), rr.gen_fn("D", 5));
+    let mut codegen = Codegen::new(&rr.funcs, CodegenOpts::default(), &[&c, &d]);
+    assert_eq!(codegen.glsl(&c), "add(_tmp0,_tmp0)");
+    assert_eq!(codegen.glsl(&d), "mult(_tmp0,_warp0(uv))");
+    // the parts of x are only used within it
+    assert_eq!(codegen.stmts, [format!("float _tmp0 = {x};")]);
+    // evaluated at other coordinates
+    assert!(codegen.helpers[0].contains(&format!("return {x};")));
+
+    let opts = CodegenOpts { cse: false };
+    let mut codegen = Codegen::new(&rr.funcs, opts, &[&c, &d]);
+    assert_eq!(codegen.glsl(&c), format!("add({x},{x})"));
+    assert!(codegen.stmts.is_empty());
+
+    // not worth declaring, unlike a repeated warp which would need two helpers
+    let rr = parse_rewrite_rules("C | add(sin(u), sin(u)) ;\nD | add(warp(uv, u), warp(uv, u)) ;")
+        .unwrap();
+    let (c, d) = (rr.gen_fn("C", 5), rr.gen_fn("D", 5));
+    let mut codegen = Codegen::new(&rr.funcs, CodegenOpts::default(), &[&c, &d]);
+    assert_eq!(codegen.glsl(&c), "add(sin(u),sin(u))");
+    assert_eq!(codegen.glsl(&d), "add(_tmp0,_tmp0)");
+    assert_eq!(codegen.helpers.len(), 1);
+}
@@ -0,0 +1,62 @@
+//! Saving and loading text files, with native dialogs on desktop and downloads/uploads on the web.
+
+use std::sync::{Arc, Mutex};
+
+use egui_inspect::logging::log::error;
+
+/// contents of a file picked for loading, once read (which is asynchronous on the web)
+pub type Loaded = Arc<Mutex<Option<String>>>;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save(file_name: &str, contents: String) {
+    let path = rfd::FileDialog::new()
+        .set_file_name(file_name)
+        .add_filter("project", &["json"])
+        .save_file();
+    if let Some(path) = path {
+        if let Err(e) = std::fs::write(&path, contents) {
+            error!("Could not write {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load(loaded: &Loaded) {
+    let path = rfd::FileDialog::new()
+        .add_filter("project", &["json"])
+        .pick_file();
+    if let Some(path) = path {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => *loaded.lock().unwrap() = Some(contents),
+            Err(e) => error!("Could not read {}: {e}", path.display()),
+        }
+    }
+}
+
+/// offers the file as a download
+#[cfg(target_arch = "wasm32")]
+pub fn save(file_name: &str, contents: String) {
+    let dialog = rfd::AsyncFileDialog::new().set_file_name(file_name);
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Some(file) = dialog.save_file().await {
+            if let Err(e) = file.write(contents.as_bytes()).await {
+                error!("Could not save {}: {e}", file.file_name());
+            }
+        }
+    });
+}
+
+/// asks for a file to upload
+#[cfg(target_arch = "wasm32")]
+pub fn load(loaded: &Loaded) {
+    let loaded = loaded.clone();
+    let dialog = rfd::AsyncFileDialog::new().add_filter("project", &["json"]);
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Some(file) = dialog.pick_file().await {
+            match String::from_utf8(file.read().await) {
+                Ok(contents) => *loaded.lock().unwrap() = Some(contents),
+                Err(_) => error!("{} is not a text file.", file.file_name()),
+            }
+        }
+    });
+}
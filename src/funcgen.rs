@@ -2,8 +2,15 @@ use lazy_static::lazy_static;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 
-use crate::parser::{Expression, RewriteRule, RewriteRules};
-use std::{collections::HashMap, fmt::Write, sync::RwLock};
+use crate::{
+    functions::FunctionRegistry,
+    parser::{Expression, RewriteRule, RewriteRules, Term, WARP},
+    types::Value,
+};
+use std::{cell::RefCell, collections::HashMap, fmt::Write, sync::RwLock};
+
+/// values of the bindings an expression may use, by name
+pub type Vars = HashMap<String, Value>;
 
 fn weighted_pick(weights: &[u16], cidx: u16) -> Option<usize> {
     let cumsum: Vec<u16> = (0..=weights.len())
@@ -16,131 +23,298 @@ fn weighted_pick(weights: &[u16], cidx: u16) -> Option<usize> {
         .map(|(i, _)| i)
 }
 
+/// the smallest and largest values in [min,max] with at most `decimals` decimals, if any
+pub(crate) fn rounded_range(min: f32, max: f32, decimals: u8) -> Option<(f32, f32)> {
+    let scale = 10f32.powi(decimals as i32);
+    let (mut lo, mut hi) = ((min * scale).round(), (max * scale).round());
+    // NOTE: with some leeway, as min and max are rarely exact in binary
+    if lo < min * scale - 1e-3 {
+        lo += 1.0;
+    }
+    if hi > max * scale + 1e-3 {
+        hi -= 1.0;
+    }
+    (lo <= hi).then_some((lo / scale, hi / scale))
+}
+
+/// draws a constant in [min,max], rounded to the precision it gets written to the shader with
+fn rand_const(min: f32, max: f32, decimals: u8) -> f32 {
+    let r: f32 = RNG.with_borrow_mut(|rng| rng.random());
+    let scale = 10f32.powi(decimals as i32);
+    let x = ((min + r * (max - min)) * scale).round() / scale;
+    let (lo, hi) = rounded_range(min, max, decimals).unwrap_or((min, max));
+    x.clamp(lo, hi)
+}
+
+/// significant digits folded constants are rounded to, so that they print briefly
+const FOLD_DIGITS: usize = 6;
+
+fn round_sig(x: f32) -> f32 {
+    format!("{:.*e}", FOLD_DIGITS - 1, x).parse().unwrap()
+}
+
+fn literal(expr: &Expression) -> Option<f32> {
+    match expr {
+        Expression::Terminal(Term::Literal(x)) => Some(*x),
+        _ => None,
+    }
+}
+
 lazy_static! {
     pub static ref SRNG: RwLock<ChaCha8Rng> = RwLock::new(ChaCha8Rng::seed_from_u64(0));
-    pub static ref RNG: RwLock<ChaCha8Rng> = RwLock::new(ChaCha8Rng::seed_from_u64(0));
+}
+
+thread_local! {
+    // NOTE: per thread, so that batch jobs can generate from several seeds in parallel
+    static RNG: RefCell<ChaCha8Rng> = RefCell::new(ChaCha8Rng::seed_from_u64(0));
+}
+
+/// resets the generation RNG (of the current thread), so that the same seed always generates the
+/// same functions
+pub fn seed_rng(seed: u64) {
+    RNG.set(ChaCha8Rng::seed_from_u64(seed));
 }
 
 impl RewriteRule {
-    pub fn choose_random(&self) -> Expression {
-        let weights: Vec<u16> = self.branches.iter().map(|b| b.weight as u16).collect();
+    fn pick_weighted(&self, candidates: &[usize]) -> usize {
+        let weights: Vec<u16> = candidates
+            .iter()
+            .map(|i| self.branches[*i].weight as u16)
+            .collect();
         let weights_total: u16 = weights.iter().cloned().sum();
-        let mut rcidx: u16 = RNG.write().unwrap().random();
+        let mut rcidx: u16 = RNG.with_borrow_mut(|rng| rng.random());
         rcidx %= weights_total;
-        let ridx = weighted_pick(&weights, rcidx).unwrap();
-        self.branches[ridx].expr.clone()
+        candidates[weighted_pick(&weights, rcidx).unwrap()]
     }
-    fn choose_terminal(&self, rules: &HashMap<String, RewriteRule>) -> Expression {
-        let rii: u8 = RNG.write().unwrap().random();
-        let rii = (rii as usize) % self.terminal_branches.len();
-        // TODO: ^effectively using uniform weights here rather than whats defined in the grammar...
-        let ridx = self.terminal_branches[rii];
-        if let Expression::ToBeReplaced { rule } = &self.branches[ridx].expr {
-            rules.get(rule).unwrap().choose_random()
-        } else {
-            unreachable!();
+    /// Chooses a branch for a leaf at `depth` (the root being at depth 1), from the branches that
+    /// can still be fully expanded within `max_depth`. If there are none, the shallowest branches
+    /// are used, which still guarantees termination as these lead to rules of lower min depth.
+    /// Either way, the choice follows the branch weights.
+    pub fn choose(&self, depth: usize, max_depth: usize) -> &Expression {
+        let budget = (max_depth + 1).saturating_sub(depth);
+        let fitting: Vec<_> = (0..self.branches.len())
+            .filter(|i| self.branches[*i].min_depth <= budget)
+            .collect();
+        if !fitting.is_empty() {
+            return &self.branches[self.pick_weighted(&fitting)].expr;
         }
+        let shallowest: Vec<_> = (0..self.branches.len())
+            .filter(|i| self.branches[*i].min_depth == self.min_depth)
+            .collect();
+        &self.branches[self.pick_weighted(&shallowest)].expr
     }
 }
 
 impl RewriteRules {
-    // TODO: warn when initial expressions are already deeper than max_depth, or ignore?
-    pub fn replace_leafs(
-        &self,
-        base_expr: Box<Expression>,
-        max_depth: usize,
-    ) -> (Box<Expression>, usize) {
-        let mut leafs = vec![];
-        let mut queue = vec![(&base_expr, 1)];
-        // NOTE: we are re-traversing on every iteration, ideally we would add new leafs as we added
-        // subtrees
-        while let Some((next, depth)) = queue.pop() {
-            match next.as_ref() {
-                Expression::Terminal(..) => {}
-                Expression::Func1 { args, .. } => {
-                    for arg in args {
-                        queue.push((arg, depth + 1));
-                    }
+    /// Chooses an expression for a leaf of `rule` at `depth`, directly following branches that
+    /// only replace with another rule (each of which counts as a level). Returns the depth the
+    /// expression ends up at.
+    fn choose(&self, rule: &str, mut depth: usize, max_depth: usize) -> (Expression, usize) {
+        let mut rule = self.rules.get(rule).unwrap();
+        loop {
+            match rule.choose(depth, max_depth) {
+                Expression::ToBeReplaced { rule: next } => {
+                    rule = self.rules.get(next).unwrap();
+                    depth += 1;
                 }
-                Expression::Func2 { args, .. } => {
-                    for arg in args {
-                        queue.push((arg, depth + 1));
+                expr => return (expr.clone(), depth),
+            }
+        }
+    }
+
+    /// Replaces all rule references in `expr` (found at `depth`), in pre-order. Every node is only
+    /// visited once, as the expressions chosen for a leaf get expanded straight away.
+    pub fn expand(&self, expr: Expression, depth: usize, max_depth: usize) -> Expression {
+        match expr {
+            Expression::Terminal(..) => expr,
+            Expression::Func { ident, args } => Expression::Func {
+                ident,
+                args: args
+                    .into_iter()
+                    .map(|arg| self.expand(arg, depth + 1, max_depth))
+                    .collect(),
+            },
+            Expression::Warp { args, ty } => Expression::Warp {
+                args: args
+                    .into_iter()
+                    .map(|arg| self.expand(arg, depth + 1, max_depth))
+                    .collect(),
+                ty,
+            },
+            Expression::ToBeReplaced { rule } => {
+                let (expr, depth) = self.choose(&rule, depth, max_depth);
+                self.expand(expr, depth, max_depth)
+            }
+        }
+    }
+
+    /// Generates a function from the `entry` rule. Its depth will be at most `max_depth`, unless
+    /// the entry rule's `min_depth` is greater than that.
+    pub fn gen_fn(&self, entry: &str, max_depth: usize) -> Box<Expression> {
+        let entry = Expression::ToBeReplaced {
+            rule: entry.to_string(),
+        };
+        let mut func = Box::new(self.expand(entry, 1, max_depth));
+        // NOTE: constants are drawn (in pre-order) once the whole function is expanded. Expanding
+        // recursively changed the order of random draws, so seeds give different functions than
+        // before that change
+        func.resolve_rand_consts();
+        func
+    }
+}
+
+impl Expression {
+    /// whether u, v, r or uv appear anywhere in the expression
+    pub fn uses_coords(&self) -> bool {
+        match self {
+            Expression::Terminal(Term::U | Term::V | Term::R | Term::UV) => true,
+            _ => self.args().iter().any(|arg| arg.uses_coords()),
+        }
+    }
+
+    /// Folds calls on constants (to functions with CPU implementations giving floats) into
+    /// literals, and applies identities that hold for any value: `add(x, 0)`, `mult(x, 1)` and
+    /// warps of expressions that do not depend on the coordinates.
+    pub fn simplify(&self, funcs: &FunctionRegistry) -> Expression {
+        match self {
+            Expression::Func { ident, args } => {
+                let args: Vec<_> = args.iter().map(|a| a.simplify(funcs)).collect();
+                let consts: Option<Vec<_>> =
+                    args.iter().map(|a| literal(a).map(Value::Float)).collect();
+                let has_cpu = funcs.get(ident).is_some_and(|def| def.cpu.is_some());
+                if let (Some(consts), true) = (consts, has_cpu) {
+                    match funcs.call(ident, &consts) {
+                        Value::Float(x) if x.is_finite() => {
+                            return Expression::Terminal(Term::Literal(round_sig(x)))
+                        }
+                        _ => {}
                     }
                 }
-                Expression::Func3 { args, .. } => {
-                    for arg in args {
-                        queue.push((arg, depth + 1));
-                    }
+                let lits: Vec<_> = args.iter().map(literal).collect();
+                // NOTE: a shader may define functions of the same names differently
+                let name = match funcs.is_builtin(ident) {
+                    true => ident.as_str(),
+                    false => "",
+                };
+                let kept = match (name, &lits[..]) {
+                    ("add", [_, Some(x)]) if *x == 0.0 => Some(0),
+                    ("add", [Some(x), _]) if *x == 0.0 => Some(1),
+                    ("mult", [_, Some(x)]) if *x == 1.0 => Some(0),
+                    ("mult", [Some(x), _]) if *x == 1.0 => Some(1),
+                    _ => None,
+                };
+                match kept {
+                    Some(i) => args.into_iter().nth(i).unwrap(),
+                    None => Expression::Func {
+                        ident: ident.clone(),
+                        args,
+                    },
                 }
-                Expression::ToBeReplaced { rule } => {
-                    leafs.push((next, depth + 1, rule.clone()));
+            }
+            Expression::Warp { args, ty } => {
+                let mut args: Vec<_> = args.iter().map(|a| a.simplify(funcs)).collect();
+                match args.last().unwrap().uses_coords() {
+                    true => Expression::Warp { args, ty: *ty },
+                    false => args.pop().unwrap(),
                 }
             }
+            Expression::Terminal(..) | Expression::ToBeReplaced { .. } => self.clone(),
         }
-        let n_leafs = leafs.len();
-        while let Some((leaf, depth, rule)) = leafs.pop() {
-            let leafp = leaf as *const Box<Expression> as *mut Box<Expression>;
-            let new_expr = if depth < max_depth {
-                self.rules.get(&rule).unwrap().choose_random()
-            } else {
-                self.rules.get(&rule).unwrap().choose_terminal(&self.rules)
-            };
-            // NOTE: seems clearly safe from a non-concurrent acces point of view, but should double
-            // check it does not cause leaks
-            let leafr = unsafe { leafp.as_mut() }.unwrap();
-            **leafr = new_expr;
+    }
+
+    /// replaces random constants with literals, drawing from the generation RNG
+    pub fn resolve_rand_consts(&mut self) {
+        match self {
+            Expression::Terminal(term @ Term::RandConst { .. }) => {
+                if let Term::RandConst { min, max, decimals } = *term {
+                    *term = Term::Literal(rand_const(min, max, decimals));
+                }
+            }
+            Expression::Terminal(..) | Expression::ToBeReplaced { .. } => {}
+            Expression::Func { args, .. } | Expression::Warp { args, .. } => {
+                args.iter_mut().for_each(|a| a.resolve_rand_consts())
+            }
         }
-        (base_expr, n_leafs)
     }
 
-    // TODO: regenerate if depth is too low (e.g. starting with terminal)
-    pub fn gen_fn(&self, max_depth: usize) -> Box<Expression> {
-        let mut func = Box::new(self.rules.get(&self.entry_point).unwrap().choose_random());
-        loop {
-            let (func_, leafs) = self.replace_leafs(func, max_depth);
-            func = func_;
-            if leafs == 0 {
-                break;
+    /// CPU reference evaluation at a given point, should match what the default fragment shader
+    /// computes for the same expression (calls to functions without a CPU implementation give NaN)
+    pub fn eval(
+        &self,
+        funcs: &FunctionRegistry,
+        vars: &Vars,
+        u: f32,
+        v: f32,
+        t: f32,
+        r: f32,
+    ) -> Value {
+        match self {
+            Expression::Terminal(term) => match term {
+                Term::U => Value::Float(u),
+                Term::V => Value::Float(v),
+                Term::T => Value::Float(t),
+                Term::R => Value::Float(r),
+                Term::UV => Value::Vec2([u, v]),
+                Term::Literal(x) => Value::Float(*x),
+                // NOTE: bindings are evaluated at the pixel, even when used inside a warp
+                Term::Var { name, ty } => vars.get(name).copied().unwrap_or(Value::nan(*ty)),
+                Term::RandConst { .. } => {
+                    panic!("Random constants need resolving before evaluation.")
+                }
+            },
+            Expression::Func { ident, args } => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|a| a.eval(funcs, vars, u, v, t, r))
+                    .collect();
+                funcs.call(ident, &args)
+            }
+            Expression::Warp { args, .. } => {
+                let (body, coords) = args.split_last().unwrap();
+                let coords: Vec<_> = coords
+                    .iter()
+                    .map(|a| a.eval(funcs, vars, u, v, t, r))
+                    .collect();
+                let (u, v) = match coords[..] {
+                    [Value::Vec2([u, v])] => (u, v),
+                    [Value::Float(u), Value::Float(v)] => (u, v),
+                    _ => (f32::NAN, f32::NAN),
+                };
+                body.eval(funcs, vars, u, v, t, (u * u + v * v).sqrt())
+            }
+            Expression::ToBeReplaced { rule } => {
+                panic!("Expression still contains unexpanded rule {rule}.")
             }
         }
-        func
     }
-}
 
-impl Expression {
     // TODO: non-recursive impl?
     pub fn as_string(&self) -> String {
         let mut buff = String::new();
         match self {
             Expression::Terminal(term) => match term {
-                crate::parser::Term::RandConst => {
-                    let mut r: f32 = RNG.write().unwrap().random();
-                    r *= 2.0;
-                    r -= 1.0;
-                    _ = buff.write_fmt(format_args!("{r:.2}"));
+                Term::RandConst { min, max, decimals } => {
+                    let r = rand_const(*min, *max, *decimals);
+                    _ = buff.write_fmt(format_args!("{r:?}"));
                 }
-                crate::parser::Term::U => _ = buff.write_str("u"),
-                crate::parser::Term::V => _ = buff.write_str("v"),
-                crate::parser::Term::T => _ = buff.write_str("t"),
-                crate::parser::Term::R => _ = buff.write_str("r"),
+                Term::U => _ = buff.write_str("u"),
+                Term::V => _ = buff.write_str("v"),
+                Term::T => _ = buff.write_str("t"),
+                Term::R => _ = buff.write_str("r"),
+                Term::UV => _ = buff.write_str("uv"),
+                // NOTE: debug formatting always includes a decimal point or exponent, as GLSL
+                // float literals require
+                Term::Literal(x) => _ = buff.write_fmt(format_args!("{x:?}")),
+                Term::Var { name, .. } => _ = buff.write_str(name),
             },
-            Expression::Func1 { ident, args } => {
-                _ = buff.write_fmt(format_args!("{ident}({})", args[0].as_string()));
-            }
-            Expression::Func2 { ident, args } => {
-                _ = buff.write_fmt(format_args!(
-                    "{ident}({},{})",
-                    args[0].as_string(),
-                    args[1].as_string()
-                ));
+            Expression::Func { ident, args } => {
+                let args: Vec<_> = args.iter().map(|a| a.as_string()).collect();
+                _ = buff.write_fmt(format_args!("{ident}({})", args.join(",")));
             }
-            Expression::Func3 { ident, args } => {
-                _ = buff.write_fmt(format_args!(
-                    "{ident}({},{},{})",
-                    args[0].as_string(),
-                    args[1].as_string(),
-                    args[2].as_string()
-                ));
+            // NOTE: not valid GLSL, see `codegen::Codegen` for that
+            Expression::Warp { args, .. } => {
+                let args: Vec<_> = args.iter().map(|a| a.as_string()).collect();
+                _ = buff.write_fmt(format_args!("{WARP}({})", args.join(",")));
             }
             Expression::ToBeReplaced { .. } => {
                 // TODO: log warn that these should be replaced by now..?
@@ -150,3 +324,84 @@ impl Expression {
         buff
     }
 }
+
+#[test]
+fn eval_test() {
+    use crate::parser::parse_rewrite_rules;
+
+    // the red channel of the default shader, mult(add(sin(t),1.0),0.5)
+    let lit = |x| Expression::Terminal(Term::Literal(x));
+    let sin_t = Expression::Func {
+        ident: "sin".to_string(),
+        args: vec![Expression::Terminal(Term::T)],
+    };
+    let red = Expression::Func {
+        ident: "mult".to_string(),
+        args: vec![
+            Expression::Func {
+                ident: "add".to_string(),
+                args: vec![sin_t, lit(1.0)],
+            },
+            lit(0.5),
+        ],
+    };
+    assert_eq!(red.as_string(), "mult(add(sin(t),1.0),0.5)");
+    let funcs = FunctionRegistry::builtin();
+    assert_eq!(
+        red.eval(&funcs, &Vars::new(), 0.0, 0.0, 0.0, 0.0),
+        Value::Float(0.5)
+    );
+    let t = std::f32::consts::FRAC_PI_2;
+    assert!((red.eval(&funcs, &Vars::new(), 0.0, 0.0, t, 0.0).comps()[0] - 1.0).abs() < 1e-6);
+
+    let src = crate::shader::DEFAULT_GRAMMAR;
+    let rr = parse_rewrite_rules(src).unwrap();
+    seed_rng(42);
+    let func = rr.gen_fn(&rr.entry_point, 10);
+    for i in 0..=10 {
+        let u = i as f32 / 5.0 - 1.0;
+        let v = -u;
+        let r = (u * u + v * v).sqrt();
+        assert!(func.eval(&rr.funcs, &Vars::new(), u, v, 1.0, r).comps()[0].is_finite());
+    }
+}
+
+#[test]
+fn forced_choice_weights_test() {
+    // cannot fit within a max depth of 1, so the shallowest branches are forced
+    let rr = crate::parser::parse_rewrite_rules("C | sin(A) ; A | abs(u) ||| abs(v) ;").unwrap();
+    seed_rng(0);
+    let n_v = (0..1000)
+        .filter(|_| rr.gen_fn("C", 1).as_string() == "sin(abs(v))")
+        .count();
+    assert!((650..850).contains(&n_v), "{n_v}");
+}
+
+#[test]
+fn simplify_test() {
+    use crate::parser::parse_rewrite_rules;
+
+    let rr = parse_rewrite_rules(
+        "C | add(mult(sin(0.5), 0.2), u) | mult(1, add(u, add(0.31, -0.31))) | warp(v, u, sin(t)) ;",
+    )
+    .unwrap();
+    let simplified: Vec<_> = rr.rules["C"]
+        .branches
+        .iter()
+        .map(|b| b.expr.simplify(&rr.funcs).as_string())
+        .collect();
+    assert_eq!(simplified, ["add(0.0958852,u)", "u", "sin(t)"]);
+    // not safe, x may be infinite
+    let rr = parse_rewrite_rules("C | mult(0, exp(u)) ;").unwrap();
+    let expr = &rr.rules["C"].branches[0].expr;
+    assert_eq!(expr.simplify(&rr.funcs).as_string(), "mult(0.0,exp(u))");
+    // not the builtin add
+    let funcs = FunctionRegistry::from_shader("float add(float x, float y) { return x - y; }");
+    let rr = crate::parser::parse_rewrite_rules_with("C | add(u, 0) | add(1, 2) ;", funcs).unwrap();
+    let simplified: Vec<_> = rr.rules["C"]
+        .branches
+        .iter()
+        .map(|b| b.expr.simplify(&rr.funcs).as_string())
+        .collect();
+    assert_eq!(simplified, ["add(u,0.0)", "add(1.0,2.0)"]);
+}
@@ -0,0 +1,573 @@
+//! Functions that grammars are allowed to call, along with how to define and evaluate them.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    codegen::HELPER_PREFIX,
+    parser::WARP,
+    types::{Type, Value},
+};
+
+/// type of a parameter or return value in a function signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigType {
+    Fixed(Type),
+    /// any of float, vec2 or vec3, but the same one wherever it appears in the signature (genType
+    /// in the GLSL spec)
+    Gen,
+}
+
+impl SigType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SigType::Fixed(ty) => ty.as_str(),
+            SigType::Gen => "genType",
+        }
+    }
+}
+
+use SigType::Gen;
+const FLOAT: SigType = SigType::Fixed(Type::Float);
+
+/// CPU implementation of a function, given arguments matching its signature
+#[derive(Debug, Clone, Copy)]
+pub enum CpuImpl {
+    /// applied per component to genType arguments, with float arguments shared by all components
+    Scalar(fn(&[f32]) -> f32),
+    Value(fn(&[Value]) -> Value),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FuncDef {
+    pub params: Vec<SigType>,
+    pub ret: SigType,
+    /// full GLSL definition, to add to the shader if it does not define the function already
+    /// (None for GLSL builtins and functions written in the shader)
+    pub glsl: Option<String>,
+    /// used for CPU rendering, calls evaluate to NaN without one (only whether there is one gets
+    /// serialised, see `FunctionRegistry::deserialize`)
+    #[serde(skip_deserializing, serialize_with = "has_cpu")]
+    pub cpu: Option<CpuImpl>,
+    /// whether this is the function `FunctionRegistry::builtin` registers, rather than one of the
+    /// same name defined differently (e.g. by the shader)
+    #[serde(skip)]
+    pub builtin: bool,
+}
+
+fn has_cpu<S: Serializer>(cpu: &Option<CpuImpl>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(cpu.is_some())
+}
+
+impl FuncDef {
+    /// a function that is defined in the shader (or by GLSL), but has no CPU counterpart
+    pub fn new(params: Vec<Type>, ret: Type) -> Self {
+        Self {
+            params: params.into_iter().map(SigType::Fixed).collect(),
+            ret: SigType::Fixed(ret),
+            glsl: None,
+            cpu: None,
+            builtin: false,
+        }
+    }
+    /// one of `FunctionRegistry::builtin`, applied per component
+    fn scalar(params: &[SigType], ret: SigType, f: fn(&[f32]) -> f32) -> Self {
+        Self {
+            params: params.to_vec(),
+            ret,
+            glsl: None,
+            cpu: Some(CpuImpl::Scalar(f)),
+            builtin: true,
+        }
+    }
+    /// one of `FunctionRegistry::builtin`
+    fn value(params: &[SigType], ret: SigType, f: fn(&[Value]) -> Value) -> Self {
+        Self {
+            params: params.to_vec(),
+            ret,
+            glsl: None,
+            cpu: Some(CpuImpl::Value(f)),
+            builtin: true,
+        }
+    }
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+    pub fn same_signature(&self, other: &Self) -> bool {
+        self.params == other.params && self.ret == other.ret
+    }
+    /// Checks the argument types against the signature, giving the return type, or the index of
+    /// the first mismatching argument with the type it was expected to have.
+    pub fn resolve(&self, args: &[Type]) -> Result<Type, (usize, Type)> {
+        let mut gen = None;
+        for (i, (param, arg)) in self.params.iter().zip(args).enumerate() {
+            let expected = match param {
+                SigType::Fixed(ty) => *ty,
+                Gen => *gen.get_or_insert(*arg),
+            };
+            if expected != *arg {
+                return Err((i, expected));
+            }
+        }
+        Ok(match self.ret {
+            SigType::Fixed(ty) => ty,
+            Gen => gen.unwrap_or(Type::Float),
+        })
+    }
+    /// like GLSL, e.g. `genType mix(genType, genType, float)`
+    pub fn signature(&self, name: &str) -> String {
+        let params: Vec<_> = self.params.iter().map(|p| p.as_str()).collect();
+        format!("{} {name}({})", self.ret.as_str(), params.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionRegistry {
+    #[serde(serialize_with = "crate::json::sorted")]
+    funcs: HashMap<String, FuncDef>,
+    /// names of the functions with GLSL definitions, in order of registration (so that each is
+    /// defined after the ones it calls)
+    defined: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for FunctionRegistry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Func {
+            #[serde(flatten)]
+            def: FuncDef,
+            #[serde(default)]
+            cpu: bool,
+        }
+        #[derive(Deserialize)]
+        struct Repr {
+            funcs: HashMap<String, Func>,
+            defined: Vec<String>,
+        }
+        let Repr { funcs, defined } = Repr::deserialize(deserializer)?;
+        if let Some(name) = defined.iter().find(|name| !funcs.contains_key(*name)) {
+            return Err(serde::de::Error::custom(format!(
+                "defined function {name} is not in funcs"
+            )));
+        }
+        // NOTE: CPU implementations cannot be serialised, so builtins that had theirs get them
+        // back (as long as the signature still matches)
+        let builtin = Self::builtin();
+        let funcs = funcs
+            .into_iter()
+            .map(|(name, Func { mut def, cpu })| {
+                if let Some(b) = builtin.get(&name).filter(|b| cpu && b.same_signature(&def)) {
+                    def.cpu = b.cpu;
+                    def.builtin = true;
+                }
+                (name, def)
+            })
+            .collect();
+        Ok(Self { funcs, defined })
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// sigmoid, as defined in default_frag.glsl
+fn sig(x: f32, x0: f32, r: f32) -> f32 {
+    // rescale -1,1 input range to 5,15
+    let rs = (r + 1.0) * 7.5 + 5.0;
+    1.0 / (1.0 + (-(rs * (x - x0))).exp())
+}
+
+/// as defined in default_frag.glsl
+fn rotate(args: &[Value]) -> Value {
+    match args {
+        [Value::Vec2([x, y]), Value::Float(a)] => {
+            let (s, c) = a.sin_cos();
+            Value::Vec2([c * x - s * y, s * x + c * y])
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn dot(args: &[Value]) -> f32 {
+    let (a, b) = (args[0].comps(), args[1].comps());
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// calls a scalar implementation per component of the genType arguments
+fn call_scalar(f: fn(&[f32]) -> f32, params: &[SigType], args: &[Value]) -> Value {
+    let width = params
+        .iter()
+        .zip(args)
+        .find(|(p, _)| **p == Gen)
+        .map(|(_, a)| a.ty().width())
+        .unwrap_or(1);
+    let mut comps = vec![0.0; args.len()];
+    let mut out = [0.0; 3];
+    for (i, out) in out.iter_mut().enumerate().take(width) {
+        for (j, (param, arg)) in params.iter().zip(args).enumerate() {
+            comps[j] = match param {
+                Gen => arg.comps()[i],
+                SigType::Fixed(_) => arg.comps()[0],
+            };
+        }
+        *out = f(&comps);
+    }
+    Value::from_comps(&out[..width])
+}
+
+impl FunctionRegistry {
+    pub fn empty() -> Self {
+        Self {
+            funcs: HashMap::new(),
+            defined: vec![],
+        }
+    }
+
+    /// the GLSL builtins we have CPU implementations for
+    pub fn glsl_builtins() -> Self {
+        let mut new = Self::empty();
+        new.register("abs", FuncDef::scalar(&[Gen], Gen, |a| a[0].abs()));
+        new.register("exp", FuncDef::scalar(&[Gen], Gen, |a| a[0].exp()));
+        new.register("sqrt", FuncDef::scalar(&[Gen], Gen, |a| a[0].sqrt()));
+        new.register("sin", FuncDef::scalar(&[Gen], Gen, |a| a[0].sin()));
+        new.register("cos", FuncDef::scalar(&[Gen], Gen, |a| a[0].cos()));
+        new.register("tan", FuncDef::scalar(&[Gen], Gen, |a| a[0].tan()));
+        new.register("floor", FuncDef::scalar(&[Gen], Gen, |a| a[0].floor()));
+        new.register(
+            "fract",
+            FuncDef::scalar(&[Gen], Gen, |a| a[0] - a[0].floor()),
+        );
+        new.register("min", FuncDef::scalar(&[Gen, Gen], Gen, |a| a[0].min(a[1])));
+        new.register("max", FuncDef::scalar(&[Gen, Gen], Gen, |a| a[0].max(a[1])));
+        new.register(
+            "pow",
+            FuncDef::scalar(&[Gen, Gen], Gen, |a| a[0].powf(a[1])),
+        );
+        new.register(
+            "mix",
+            FuncDef::scalar(&[Gen, Gen, FLOAT], Gen, |a| {
+                a[0] * (1.0 - a[2]) + a[1] * a[2]
+            }),
+        );
+        // NOTE: not f32::clamp, which panics where GLSL is merely undefined (lo > hi)
+        new.register(
+            "clamp",
+            FuncDef::scalar(&[Gen, FLOAT, FLOAT], Gen, |a| a[0].max(a[1]).min(a[2])),
+        );
+        new.register(
+            "smoothstep",
+            FuncDef::scalar(&[FLOAT, FLOAT, Gen], Gen, |a| {
+                let s = ((a[2] - a[0]) / (a[1] - a[0])).clamp(0.0, 1.0);
+                s * s * (3.0 - 2.0 * s)
+            }),
+        );
+        new.register(
+            "length",
+            FuncDef::value(&[Gen], FLOAT, |a| Value::Float(dot(&[a[0], a[0]]).sqrt())),
+        );
+        new.register(
+            "dot",
+            FuncDef::value(&[Gen, Gen], FLOAT, |a| Value::Float(dot(a))),
+        );
+        new.register(
+            "vec2",
+            FuncDef::value(&[FLOAT, FLOAT], SigType::Fixed(Type::Vec2), |a| {
+                Value::from_comps(&[a[0].comps()[0], a[1].comps()[0]])
+            }),
+        );
+        new.register(
+            "vec3",
+            FuncDef::value(&[FLOAT, FLOAT, FLOAT], SigType::Fixed(Type::Vec3), |a| {
+                Value::from_comps(&[a[0].comps()[0], a[1].comps()[0], a[2].comps()[0]])
+            }),
+        );
+        new
+    }
+
+    /// the GLSL builtins along with the helpers defined in default_frag.glsl
+    pub fn builtin() -> Self {
+        let mut new = Self::glsl_builtins();
+        new.register(
+            "add",
+            FuncDef::scalar(&[FLOAT, FLOAT], FLOAT, |a| a[0] + a[1]),
+        );
+        new.register(
+            "mult",
+            FuncDef::scalar(&[FLOAT, FLOAT], FLOAT, |a| a[0] * a[1]),
+        );
+        new.register(
+            "sig",
+            FuncDef::scalar(&[FLOAT, FLOAT, FLOAT], FLOAT, |a| sig(a[0], a[1], a[2])),
+        );
+        let vec2 = SigType::Fixed(Type::Vec2);
+        new.register("rotate", FuncDef::value(&[vec2, FLOAT], vec2, rotate));
+        new
+    }
+
+    /// The GLSL builtins along with the float, vec2 and vec3 functions the fragment shader
+    /// defines (e.g. `float name(float a, vec2 b)`), so that grammars can call exactly what the
+    /// shader has. Helpers defined exactly as in default_frag.glsl keep their CPU implementation,
+    /// others have none.
+    pub fn from_shader(code: &str) -> Self {
+        let known = Self::builtin();
+        let mut new = Self::glsl_builtins();
+        for (name, params, ret) in scan_funcs(code) {
+            // NOTE: `warp(...)` always parses as `Expression::Warp`, so could never be called
+            if name.starts_with(HELPER_PREFIX) || name == WARP {
+                continue;
+            }
+            let def = FuncDef::new(params, ret);
+            let shipped = || {
+                let default = func_tokens(crate::shader::DEFAULT_FRAG, &name);
+                default.is_some() && default == func_tokens(code, &name)
+            };
+            let def = match known.get(&name) {
+                Some(known) if known.same_signature(&def) && shipped() => known.clone(),
+                _ => def,
+            };
+            new.register(name, def);
+        }
+        new
+    }
+
+    /// adds a function, replacing any previous one of the same name
+    pub fn register(&mut self, name: impl Into<String>, def: FuncDef) {
+        let name = name.into();
+        self.defined.retain(|n| *n != name);
+        if def.glsl.is_some() {
+            self.defined.push(name.clone());
+        }
+        self.funcs.insert(name, def);
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<FuncDef> {
+        self.defined.retain(|n| n != name);
+        self.funcs.remove(name)
+    }
+
+    /// the functions with GLSL definitions, in the order they were registered
+    pub fn defined(&self) -> impl Iterator<Item = (&String, &FuncDef)> {
+        self.defined.iter().map(|name| (name, &self.funcs[name]))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FuncDef> {
+        self.funcs.get(name)
+    }
+
+    /// whether the function is the one from `builtin`, rather than something of the same name
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|def| def.builtin)
+    }
+
+    /// sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &FuncDef)> {
+        let mut funcs: Vec<_> = self.funcs.iter().collect();
+        funcs.sort_by(|a, b| a.0.cmp(b.0));
+        funcs.into_iter()
+    }
+
+    /// Same names and signatures, leaving out functions with GLSL definitions of their own (e.g.
+    /// from `@func`), as those do not come from the shader.
+    pub fn same_signatures(&self, other: &Self) -> bool {
+        let mut a = self.iter().filter(|(_, def)| def.glsl.is_none());
+        let mut b = other.iter().filter(|(_, def)| def.glsl.is_none());
+        loop {
+            match (a.next(), b.next()) {
+                (None, None) => return true,
+                (Some((n, def)), Some((m, d))) if n == m && d.same_signature(def) => {}
+                _ => return false,
+            }
+        }
+    }
+
+    /// evaluates a call on the CPU, NaN if the function is unknown or has no CPU implementation
+    pub fn call(&self, name: &str, args: &[Value]) -> Value {
+        let Some(def) = self.get(name) else {
+            return Value::Float(f32::NAN);
+        };
+        match def.cpu {
+            Some(CpuImpl::Scalar(f)) => call_scalar(f, &def.params, args),
+            Some(CpuImpl::Value(f)) => f(args),
+            None => {
+                let args: Vec<_> = args.iter().map(|a| a.ty()).collect();
+                Value::nan(def.resolve(&args).unwrap_or(Type::Float))
+            }
+        }
+    }
+}
+
+/// splits GLSL into identifiers/numbers and single punctuation characters, skipping comments
+fn glsl_tokens(code: &str) -> Vec<&str> {
+    let mut toks = vec![];
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let len = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("/*") {
+            rest.find("*/").map(|i| i + 2).unwrap_or(rest.len())
+        } else if c.is_whitespace() {
+            c.len_utf8()
+        } else if c.is_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            toks.push(&rest[..len]);
+            len
+        } else {
+            toks.push(&rest[..c.len_utf8()]);
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    toks
+}
+
+/// parameter types, if all are float, vec2 or vec3, e.g. `float a, in vec2 b`
+fn param_types(params: &[&str]) -> Option<Vec<Type>> {
+    if params.is_empty() || params == ["void"] {
+        return Some(vec![]);
+    }
+    let mut types = vec![];
+    for param in params.split(|tok| *tok == ",") {
+        let param: Vec<_> = param
+            .iter()
+            .filter(|tok| !matches!(**tok, "in" | "const" | "lowp" | "mediump" | "highp"))
+            .collect();
+        match param[..] {
+            [ty, _] => types.push(Type::from_name(ty)?),
+            _ => return None,
+        }
+    }
+    Some(types)
+}
+
+/// the tokens of a function's definition (signature and body), ignoring whitespace and comments
+fn func_tokens<'a>(code: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    let toks = glsl_tokens(code);
+    // NOTE: skipping prototypes, which have no body
+    let (start, open) = (1..toks.len().saturating_sub(1)).find_map(|i| {
+        if toks[i] != name || toks[i + 1] != "(" || Type::from_name(toks[i - 1]).is_none() {
+            return None;
+        }
+        let close = i + toks[i..].iter().position(|tok| *tok == ")")?;
+        (toks.get(close + 1) == Some(&"{")).then_some((i - 1, close + 1))
+    })?;
+    let mut level = 0;
+    for (i, tok) in toks.iter().enumerate().skip(open) {
+        match *tok {
+            "{" => level += 1,
+            "}" => level -= 1,
+            _ => {}
+        }
+        if level == 0 {
+            return Some(toks[start..=i].to_vec());
+        }
+    }
+    None
+}
+
+fn is_ident(tok: &str) -> bool {
+    tok.starts_with(|c: char| c.is_alphabetic() || c == '_')
+}
+
+/// Finds the names, parameter types and return types of functions declared as e.g.
+/// `float name(float a, vec2 b)`.
+pub fn scan_funcs(code: &str) -> Vec<(String, Vec<Type>, Type)> {
+    let toks = glsl_tokens(code);
+    let mut found = vec![];
+    let mut i = 0;
+    while i + 2 < toks.len() {
+        let ret = Type::from_name(toks[i]).filter(|_| is_ident(toks[i + 1]) && toks[i + 2] == "(");
+        if let Some(ret) = ret {
+            if let Some(close) = toks[i + 3..].iter().position(|tok| *tok == ")") {
+                let params = &toks[i + 3..i + 3 + close];
+                if let Some(params) = param_types(params) {
+                    found.push((toks[i + 1].to_string(), params, ret));
+                }
+                i += 3 + close;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+#[test]
+fn scan_funcs_test() {
+    let funcs = FunctionRegistry::from_shader(crate::shader::DEFAULT_FRAG);
+    for name in ["add", "mult", "sig", "rotate"] {
+        assert!(funcs.get(name).unwrap().cpu.is_some());
+    }
+
+    let code = "
+        // float commented(float x) {}
+        float add(float x) { return x; }
+        float warp(float a, in float b, const float c,
+                   float d) { return a; }
+        vec2 rot(vec2 p, float a) { return p; }
+        float twice(int n) { return 2.0; }
+        void main() { float red = add(float(1)); }";
+    let found = scan_funcs(code);
+    let names: Vec<_> = found.iter().map(|f| f.0.as_str()).collect();
+    assert_eq!(names, ["add", "warp", "rot"]);
+    assert_eq!(found[1].1.len(), 4);
+    let funcs = FunctionRegistry::from_shader(code);
+    assert!(funcs.get("add").unwrap().cpu.is_none());
+    assert!(funcs.get("mult").is_none());
+    // only helpers defined as in the default shader keep their CPU implementation
+    let code = "float add(float x, float y) { return x - y; }";
+    assert!(FunctionRegistry::from_shader(code)
+        .get("add")
+        .unwrap()
+        .cpu
+        .is_none());
+    let add = func_tokens(crate::shader::DEFAULT_FRAG, "add")
+        .unwrap()
+        .join(" ");
+    let code = format!("float add(float, float);\n{add}");
+    assert!(FunctionRegistry::from_shader(&code)
+        .get("add")
+        .unwrap()
+        .cpu
+        .is_some());
+    assert!(funcs.get("warp").is_none());
+    assert_eq!(
+        funcs.get("rot").unwrap().signature("rot"),
+        "vec2 rot(vec2, float)"
+    );
+
+    let mix = funcs.get("mix").unwrap();
+    assert_eq!(
+        mix.resolve(&[Type::Vec3, Type::Vec3, Type::Float]),
+        Ok(Type::Vec3)
+    );
+    assert_eq!(
+        mix.resolve(&[Type::Vec3, Type::Vec2, Type::Float]),
+        Err((1, Type::Vec3))
+    );
+    let mixed = funcs.call(
+        "mix",
+        &[
+            Value::Vec2([0.0, 1.0]),
+            Value::Vec2([1.0, 3.0]),
+            Value::Float(0.5),
+        ],
+    );
+    assert_eq!(mixed, Value::Vec2([0.5, 2.0]));
+}
+
+#[test]
+fn same_signatures_test() {
+    use crate::{parser::parse_rewrite_rules_with, shader::DEFAULT_FRAG};
+
+    let funcs = FunctionRegistry::from_shader(DEFAULT_FRAG);
+    let rr = parse_rewrite_rules_with("@func f(x) = sin(x);\nC | f(u) ;", funcs.clone()).unwrap();
+    assert!(funcs.same_signatures(&rr.funcs));
+    let code = format!("float g(float x) {{ return x; }}\n{DEFAULT_FRAG}");
+    assert!(!FunctionRegistry::from_shader(&code).same_signatures(&rr.funcs));
+}
@@ -0,0 +1,430 @@
+//! CPU rendering of generated functions to images, for when there is no GPU around (batch jobs,
+//! thumbnails, regression images).
+
+use std::{collections::HashMap, fs::File, io::BufWriter, io::Write, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codegen::{Codegen, CodegenOpts},
+    funcgen::{seed_rng, Vars},
+    functions::FunctionRegistry,
+    parser::{
+        parse_expression, parse_rewrite_rules, Expression, ParseError, ParseFail, RewriteRules,
+        CHANNELS, COLOR,
+    },
+    shader::{missing_func_defs, Template, TemplateError},
+    types::{Type, Value},
+};
+
+#[derive(Debug)]
+pub enum RenderError {
+    Parse(ParseError),
+    Io(std::io::Error),
+    Encoding(png::EncodingError),
+}
+
+impl From<ParseError> for RenderError {
+    fn from(value: ParseError) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl From<std::io::Error> for RenderError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<png::EncodingError> for RenderError {
+    fn from(value: png::EncodingError) -> Self {
+        Self::Encoding(value)
+    }
+}
+
+/// 8 bit rgb image
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// row major, starting from the top row
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn write_png<W: Write>(&self, w: W) -> Result<(), RenderError> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), RenderError> {
+        self.write_png(BufWriter::new(File::create(path)?))
+    }
+}
+
+/// same as the framebuffer would do with the fragment shader output
+fn to_u8(c: f32) -> u8 {
+    // NOTE: NaN gets mapped to 0
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// a template variable to generate, e.g. `red` from the entry rule for red
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Slot {
+    pub name: String,
+    pub entry: String,
+    pub max_depth: usize,
+}
+
+impl Slot {
+    /// The channels, or the whole colour for grammars with a `color` entry, each generated from
+    /// its entry rule.
+    pub fn defaults(rr: &RewriteRules, max_depth: usize) -> Vec<Self> {
+        let slot = |name: &str, entry: &str| Self {
+            name: name.to_string(),
+            entry: entry.to_string(),
+            max_depth,
+        };
+        match rr.color_entry() {
+            Some(entry) => vec![slot(COLOR, entry)],
+            None => CHANNELS
+                .iter()
+                .map(|channel| slot(channel, rr.entry(channel)))
+                .collect(),
+        }
+    }
+    /// Checks that the entry rule exists and can be fully expanded, which `gen_slots` relies on
+    /// (it would recurse forever otherwise).
+    pub fn check(&self, rr: &RewriteRules) -> Result<(), ParseFail> {
+        match rr.rules.get(&self.entry) {
+            None => Err(ParseFail::UndefinedRule(self.entry.clone())),
+            Some(rule) if rule.min_depth == usize::MAX => {
+                Err(ParseFail::UnproductiveEntryPoint(self.entry.clone()))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// a value generated from a rule
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Binding {
+    pub name: String,
+    pub ty: Type,
+    pub expr: Box<Expression>,
+}
+
+/// everything generated from a seed
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GeneratedFuncs {
+    /// in order of declaration, each only using those before it
+    pub lets: Vec<Binding>,
+    /// named after their slots
+    pub slots: Vec<Binding>,
+}
+
+impl GeneratedFuncs {
+    /// Rebuilds generated functions from their strings (see `parser::parse_expression`), e.g.
+    /// after editing them, giving the name of the first one that fails to parse otherwise. Each
+    /// binding may use those before it and the slots may use all of them.
+    pub fn from_strings(
+        lets: &[(String, String)],
+        slots: &[(String, String)],
+        funcs: &FunctionRegistry,
+    ) -> Result<Self, (String, ParseError)> {
+        let mut vars = vec![];
+        let mut parse = |name: &String, src: &String, is_let: bool| {
+            let (mut expr, ty) =
+                parse_expression(src, funcs, &vars).map_err(|e| (name.clone(), e))?;
+            expr.resolve_rand_consts();
+            if is_let {
+                vars.push((name.clone(), ty));
+            }
+            Ok(Binding {
+                name: name.clone(),
+                ty,
+                expr: Box::new(expr),
+            })
+        };
+        let lets = lets
+            .iter()
+            .map(|(name, src)| parse(name, src, true))
+            .collect::<Result<_, _>>()?;
+        let slots = slots
+            .iter()
+            .map(|(name, src)| parse(name, src, false))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { lets, slots })
+    }
+    pub fn slot(&self, name: &str) -> Option<&Binding> {
+        self.slots.iter().find(|slot| slot.name == name)
+    }
+    /// The colour the default shader would give, from the `color` slot or the channel slots
+    /// (missing channels being 0).
+    pub fn eval(&self, funcs: &FunctionRegistry, u: f32, v: f32, t: f32, r: f32) -> [f32; 3] {
+        let mut vars = Vars::new();
+        for binding in self.lets.iter() {
+            let value = binding.expr.eval(funcs, &vars, u, v, t, r);
+            vars.insert(binding.name.clone(), value);
+        }
+        let eval = |name| {
+            self.slot(name)
+                .map(|slot| slot.expr.eval(funcs, &vars, u, v, t, r))
+        };
+        match eval(COLOR) {
+            Some(Value::Vec3(rgb)) => rgb,
+            Some(_) => [f32::NAN; 3],
+            None => CHANNELS.map(|channel| match eval(channel) {
+                Some(value) => value.comps()[0],
+                None => 0.0,
+            }),
+        }
+    }
+    /// see `Expression::simplify`
+    pub fn simplify(&mut self, funcs: &FunctionRegistry) {
+        for binding in self.lets.iter_mut().chain(self.slots.iter_mut()) {
+            *binding.expr = binding.expr.simplify(funcs);
+        }
+    }
+    fn exprs(&self) -> Vec<&Expression> {
+        self.lets
+            .iter()
+            .chain(self.slots.iter())
+            .map(|b| &*b.expr)
+            .collect()
+    }
+    /// Fills the template's placeholders with the slots' functions, along with the definitions
+    /// and statements they need (see `Template::render`). With a `color` slot but no channel
+    /// slots `{{red}}`, `{{green}}` and `{{blue}}` get its components, and the other way around
+    /// `{{color}}` gets the channels as a vec3.
+    pub fn render(
+        &self,
+        template: &str,
+        funcs: &FunctionRegistry,
+        opts: CodegenOpts,
+    ) -> Result<String, TemplateError> {
+        let mut codegen = Codegen::new(funcs, opts, &self.exprs());
+        for binding in self.lets.iter() {
+            codegen.bind(&binding.name, binding.ty, &binding.expr);
+        }
+        let mut values = HashMap::new();
+        let has_channels = CHANNELS.iter().any(|channel| self.slot(channel).is_some());
+        for slot in self.slots.iter() {
+            if slot.name == COLOR && !has_channels {
+                codegen.bind("rgb", Type::Vec3, &slot.expr);
+                values.insert(COLOR.to_string(), "rgb".to_string());
+                for (channel, comp) in CHANNELS.iter().zip(["r", "g", "b"]) {
+                    values.insert(channel.to_string(), format!("rgb.{comp}"));
+                }
+            } else {
+                values.insert(slot.name.clone(), codegen.glsl(&slot.expr));
+            }
+        }
+        if has_channels && !values.contains_key(COLOR) {
+            let channels = CHANNELS.map(|channel| values.get(channel).map_or("0.0", |v| v));
+            values.insert(COLOR.to_string(), format!("vec3({})", channels.join(",")));
+        }
+        let mut defs = missing_func_defs(template, funcs);
+        defs.extend(codegen.helpers);
+        Template::new(template).render(&values, &defs, &codegen.stmts)
+    }
+}
+
+/// The slots (by name) whose values the template never uses, directly or through `{{color}}` or
+/// the channels (see `GeneratedFuncs::render`), e.g. as their names are misspelled.
+pub fn unused_slots<'a>(template: &str, slots: &[&'a str]) -> Vec<&'a str> {
+    let template = Template::new(template);
+    let has_channels = slots.iter().any(|name| CHANNELS.contains(name));
+    slots
+        .iter()
+        .copied()
+        .filter(|name| {
+            let indirectly = match *name {
+                COLOR if !has_channels => CHANNELS.iter().any(|channel| template.uses(channel)),
+                _ if CHANNELS.contains(name) => template.uses(COLOR),
+                _ => false,
+            };
+            !template.uses(name) && !indirectly
+        })
+        .collect()
+}
+
+/// Generates the bindings (at `max_depth`) and then the slots for a seed, the same way the app
+/// does. The slots need to pass `Slot::check`.
+pub fn gen_slots(rr: &RewriteRules, seed: u64, max_depth: usize, slots: &[Slot]) -> GeneratedFuncs {
+    seed_rng(seed);
+    let gen = |name: &str, rule: &str, max_depth| Binding {
+        name: name.to_string(),
+        ty: rr.rules[rule].ty,
+        expr: rr.gen_fn(rule, max_depth),
+    };
+    let lets = rr
+        .lets
+        .iter()
+        .map(|(name, rule)| gen(name, rule, max_depth))
+        .collect();
+    let slots = slots
+        .iter()
+        .map(|slot| gen(&slot.name, &slot.entry, slot.max_depth))
+        .collect();
+    GeneratedFuncs { lets, slots }
+}
+
+/// Generates the bindings and the default slots for a seed.
+pub fn gen_channels(rr: &RewriteRules, seed: u64, max_depth: usize) -> GeneratedFuncs {
+    gen_slots(rr, seed, max_depth, &Slot::defaults(rr, max_depth))
+}
+
+/// Evaluates the generated functions at every pixel center, with uv spanning [-1,1]x[-1,1] as on
+/// the viewport quad.
+pub fn render_channels(
+    funcs: &FunctionRegistry,
+    generated: &GeneratedFuncs,
+    t: f32,
+    width: u32,
+    height: u32,
+) -> Image {
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        // v points up
+        let v = 1.0 - 2.0 * (y as f32 + 0.5) / height as f32;
+        for x in 0..width {
+            let u = 2.0 * (x as f32 + 0.5) / width as f32 - 1.0;
+            let r = (u * u + v * v).sqrt();
+            for c in generated.eval(funcs, u, v, t, r) {
+                pixels.push(to_u8(c));
+            }
+        }
+    }
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// Parses a grammar, generates functions from a seed and renders them at time t.
+pub fn render_grammar(
+    grammar: &str,
+    seed: u64,
+    max_depth: usize,
+    t: f32,
+    width: u32,
+    height: u32,
+) -> Result<Image, RenderError> {
+    let rr = parse_rewrite_rules(grammar)?;
+    let color = gen_channels(&rr, seed, max_depth);
+    Ok(render_channels(&rr.funcs, &color, t, width, height))
+}
+
+#[test]
+fn render_test() {
+    let src = crate::shader::DEFAULT_GRAMMAR;
+    let img = render_grammar(src, 7, 10, 0.5, 32, 18).unwrap();
+    assert_eq!(img.pixels.len(), 32 * 18 * 3);
+    let again = render_grammar(src, 7, 10, 0.5, 32, 18).unwrap();
+    assert_eq!(img.pixels, again.pixels);
+
+    let mut png = vec![];
+    img.write_png(&mut png).unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+}
+
+#[test]
+fn color_entry_test() {
+    let src = "@entry color = Col;\nC | u ;\nvec3 Col | vec3(C, length(P), C) ;\nvec2 P | uv | rotate(P, t) ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let color = gen_channels(&rr, 0, 5);
+    let img = render_channels(&rr.funcs, &color, 0.0, 2, 1);
+    // left then right pixel, at u = -0.5 and 0.5, with |uv| = 0.5 either way
+    assert_eq!(img.pixels, [0, 128, 0, 128, 128, 128]);
+    let code = color
+        .render(crate::shader::DEFAULT_FRAG, &rr.funcs, Default::default())
+        .unwrap();
+    assert!(code.contains("vec3 rgb = vec3(u,length("));
+    assert!(code.contains("float blue = rgb.b;"));
+}
+
+#[test]
+fn let_test() {
+    let src = "@let a0 = A;\nC | a0 ;\nA | sin(u) ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let generated = gen_channels(&rr, 0, 5);
+    let img = render_channels(&rr.funcs, &generated, 0.0, 2, 1);
+    // every channel shares the binding, sin(u) at u = -0.5 and 0.5
+    assert_eq!(img.pixels, [0, 0, 0, 122, 122, 122]);
+    let code = generated
+        .render(crate::shader::DEFAULT_FRAG, &rr.funcs, Default::default())
+        .unwrap();
+    assert!(code.contains("    float a0 = sin(u);"));
+    assert!(code.contains("    float green = a0;"));
+}
+
+#[test]
+fn slots_test() {
+    let src = "C | u ;\nA | abs(v) ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let mut slots = Slot::defaults(&rr, 5);
+    slots.push(Slot {
+        name: "alpha".to_string(),
+        entry: "A".to_string(),
+        max_depth: 3,
+    });
+    let generated = gen_slots(&rr, 0, 5, &slots);
+    assert_eq!(generated.slot("alpha").unwrap().expr.as_string(), "abs(v)");
+    let template = "{{@defs}}\nvoid main() {\n    color = vec4({{color}}, {{alpha}});\n}";
+    let code = generated
+        .render(template, &rr.funcs, Default::default())
+        .unwrap();
+    assert!(code.contains("color = vec4(vec3(u,u,u), abs(v));"));
+    assert!(unused_slots(template, &["red", "green", "blue", "alpha"]).is_empty());
+    let frag = crate::shader::DEFAULT_FRAG;
+    assert_eq!(unused_slots(frag, &["color", "aplha"]), ["aplha"]);
+
+    let rr = parse_rewrite_rules("C | u | X ;\nX | sin(X) ;").unwrap();
+    let mut slot = slots.pop().unwrap();
+    slot.entry = "X".to_string();
+    assert!(matches!(
+        slot.check(&rr),
+        Err(ParseFail::UnproductiveEntryPoint(_))
+    ));
+    slot.entry = "Y".to_string();
+    assert!(matches!(slot.check(&rr), Err(ParseFail::UndefinedRule(_))));
+}
+
+#[test]
+fn from_strings_test() {
+    let funcs = FunctionRegistry::builtin();
+    let lets = [("a0".to_string(), "rotate(uv,t)".to_string())];
+    let slots = [(
+        COLOR.to_string(),
+        "vec3(length(a0),u,mult(v,2.0))".to_string(),
+    )];
+    let generated = GeneratedFuncs::from_strings(&lets, &slots, &funcs).unwrap();
+    assert_eq!(generated.lets[0].ty, Type::Vec2);
+    assert_eq!(generated.eval(&funcs, 0.6, 0.8, 0.0, 1.0), [1.0, 0.6, 1.6]);
+
+    let slots = [("red".to_string(), "sin(a1)".to_string())];
+    let (name, _) = GeneratedFuncs::from_strings(&lets, &slots, &funcs)
+        .err()
+        .unwrap();
+    assert_eq!(name, "red");
+
+    // generated functions are a single line, usually far longer than 255 chars
+    let src = crate::shader::DEFAULT_GRAMMAR;
+    let rr = parse_rewrite_rules(src).unwrap();
+    let generated = gen_channels(&rr, 3, 15);
+    let strings = |bindings: &[Binding]| -> Vec<_> {
+        bindings
+            .iter()
+            .map(|b| (b.name.clone(), b.expr.as_string()))
+            .collect()
+    };
+    let slots = strings(&generated.slots);
+    assert!(slots.iter().any(|(_, src)| src.len() > 255));
+    let reparsed = GeneratedFuncs::from_strings(&[], &slots, &rr.funcs).unwrap();
+    assert_eq!(strings(&reparsed.slots), slots);
+}
@@ -0,0 +1,65 @@
+//! A stable JSON form for parsed grammars and generated functions, so that other tools can load
+//! them (and the exact functions behind a shader can be kept without relying on seeds).
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{de::DeserializeOwned, Serialize, Serializer};
+
+pub type JsonError = serde_json::Error;
+
+/// e.g. of `RewriteRules`, `GeneratedFuncs` or an `Expression`
+pub fn to_json<T: Serialize>(value: &T) -> Result<String, JsonError> {
+    serde_json::to_string_pretty(value)
+}
+
+pub fn from_json<T: DeserializeOwned>(src: &str) -> Result<T, JsonError> {
+    serde_json::from_str(src)
+}
+
+/// serialises a map ordered by key, so that the same map always gives the same JSON
+pub(crate) fn sorted<V: Serialize, S: Serializer>(
+    map: &HashMap<String, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+#[test]
+fn json_test() {
+    use crate::{
+        functions::FunctionRegistry,
+        headless::{gen_channels, GeneratedFuncs},
+        parser::{parse_rewrite_rules, RewriteRules},
+    };
+
+    let src = "@let a0 = A;\nC | sin(C) | warp(uv, a0) | mult(random, t) ;\nA | add(u, v) ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let json = to_json(&rr).unwrap();
+    assert_eq!(json, to_json(&rr).unwrap());
+    let loaded: RewriteRules = from_json(&json).unwrap();
+    assert_eq!(loaded.lets, rr.lets);
+    assert_eq!(loaded.rules["C"].branches.len(), 3);
+    // generates the same as the original
+    let generated = gen_channels(&rr, 4, 6);
+    let again = gen_channels(&loaded, 4, 6);
+    assert_eq!(to_json(&generated).unwrap(), to_json(&again).unwrap());
+
+    let loaded: GeneratedFuncs = from_json(&to_json(&generated).unwrap()).unwrap();
+    assert_eq!(
+        loaded.slots[0].expr.as_string(),
+        generated.slots[0].expr.as_string()
+    );
+    // CPU implementations of builtins are restored
+    let funcs: FunctionRegistry = from_json(&to_json(&rr.funcs).unwrap()).unwrap();
+    assert!(funcs.is_builtin("add") && funcs.is_builtin("sin"));
+    assert_eq!(
+        loaded.eval(&funcs, 0.1, 0.2, 0.3, 0.0),
+        generated.eval(&rr.funcs, 0.1, 0.2, 0.3, 0.0)
+    );
+    // but not functions of the same signature written differently in the shader
+    let funcs = FunctionRegistry::from_shader("float add(float x, float y) { return x - y; }");
+    let funcs: FunctionRegistry = from_json(&to_json(&funcs).unwrap()).unwrap();
+    assert!(funcs.get("add").unwrap().cpu.is_none() && !funcs.is_builtin("add"));
+    assert!(from_json::<RewriteRules>("{}").is_err());
+    assert!(from_json::<FunctionRegistry>(r#"{"funcs":{},"defined":["f"]}"#).is_err());
+}
@@ -0,0 +1,12 @@
+//! Grammar parsing and random function generation, usable without the GUI.
+
+pub mod codegen;
+pub mod funcgen;
+pub mod functions;
+pub mod headless;
+pub mod json;
+pub mod parser;
+pub mod project;
+pub mod shader;
+pub mod tokeniser;
+pub mod types;
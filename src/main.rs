@@ -6,96 +6,101 @@ use egui_inspect::{
         glow::{self, HasContext},
         CreationContext,
     },
-    egui::{
-        self, text::LayoutJob, vec2, CentralPanel, LayerId, ScrollArea, Sense, Shape, TextEdit,
-        Window,
-    },
+    egui::{self, vec2, CentralPanel, LayerId, ScrollArea, Sense, Shape, TextEdit, Window},
     logging::{
         default_mixed_logger,
-        log::{self, error, info, warn},
+        log::{self, error, warn},
         LogsView,
     },
     EframeMain, EguiInspect, InspectNumber,
 };
-use funcgen::{RNG, SRNG};
-use parser::{parse_rewrite_rules, Expression, RewriteRules};
-use rand::{Rng, SeedableRng};
-use rand_chacha::ChaCha8Rng;
+use rand::Rng;
+use shadergen::{
+    codegen::CodegenOpts,
+    funcgen::SRNG,
+    functions::FunctionRegistry,
+    headless::{gen_slots, unused_slots, GeneratedFuncs, Slot},
+    parser::{parse_rewrite_rules_with, RewriteRules},
+    project::{Project, VERSION},
+    shader::{DEFAULT_FRAG, DEFAULT_GRAMMAR},
+};
 use ui::CodeEdit;
 use viewport_quad::ViewportQuad;
 
-mod funcgen;
-mod parser;
-mod tokeniser;
+mod files;
 mod ui;
 mod viewport_quad;
 
+/// shows a generated function for editing, scrolling beyond `height`
+fn edit_generated(ui: &mut egui::Ui, label: &str, generated: &mut String, height: f32) {
+    if !generated.is_empty() {
+        ui.label(format!("generated {label}:"));
+        ScrollArea::vertical()
+            .id_salt(label)
+            .max_height(height)
+            .show(ui, |ui| {
+                ui.add(
+                    TextEdit::multiline(generated)
+                        .code_editor()
+                        .desired_rows(1)
+                        .desired_width(ui.available_width()),
+                );
+            });
+    }
+}
+
+/// a slot to generate a function for, along with the last one generated
 struct GeneratedFunc {
-    generated: Box<Expression>,
+    slot: Slot,
     generated_str: String,
     pub height: f32,
 }
 
-impl Default for GeneratedFunc {
-    fn default() -> Self {
+impl GeneratedFunc {
+    fn new(slot: Slot) -> Self {
         Self {
-            generated: Box::new(Expression::Terminal(parser::Term::T)),
+            slot,
             generated_str: Default::default(),
             height: 50.0,
         }
     }
-}
 
-impl GeneratedFunc {
-    fn regen(&mut self, rr: &RewriteRules, max_depth: usize) {
-        self.generated = rr.gen_fn(max_depth);
-        self.generated_str = self.generated.as_string();
-    }
-}
-
-impl EguiInspect for GeneratedFunc {
-    fn inspect(&self, label: &str, ui: &mut egui::Ui) {
-        if !self.generated_str.is_empty() {
-            ui.label(format!("generated {label} func:"));
-            ScrollArea::vertical()
-                .id_salt(label)
-                .max_height(self.height)
-                .show(ui, |ui| {
-                    let mut job = LayoutJob::default();
-                    job.wrap.max_width = ui.available_width();
-                    job.append(&self.generated_str, 0.0, Default::default());
-                    ui.label(job);
-                });
-        }
-    }
-}
-
-fn find_var_decl_line(code: &str, var: &str) -> Option<usize> {
-    // NOTE: will fail if spacing varies around these tokens...
-    let prefix = format!("float {var} = ");
-    let suffix = ";";
-    for (i, line) in code.lines().enumerate() {
-        if line.trim().starts_with(prefix.as_str()) {
-            if line.trim().ends_with(suffix) {
-                return Some(i);
-            } else {
-                info!("Declaration for variable {var} found, but line does not end in \";\". To keep things simple, please keep the declaration all on one line.");
-            }
-        }
+    /// returns whether removing the slot was asked for
+    fn inspect_row(&mut self, label: &str, ui: &mut egui::Ui) -> bool {
+        let mut remove = false;
+        ui.horizontal(|ui| {
+            remove = ui.button("remove").clicked();
+            ui.label("name:");
+            ui.add(TextEdit::singleline(&mut self.slot.name).desired_width(60.0));
+            ui.label("entry:");
+            ui.add(TextEdit::singleline(&mut self.slot.entry).desired_width(60.0));
+            self.slot
+                .max_depth
+                .inspect_with_slider("max_depth", ui, 1.0, 25.0);
+        });
+        edit_generated(ui, label, &mut self.generated_str, self.height);
+        remove
     }
-    None
 }
 
 #[derive(EframeMain)]
 #[eframe_main(no_eframe_app_derive, init = "ShaderGen::init(_cc)")]
 struct ShaderGen {
     grammar: CodeEdit,
+    /// template with placeholders for the generated functions
     frag: CodeEdit,
+    /// the template filled in, as compiled
+    rendered: CodeEdit,
+    /// parsed with the functions found in the fragment shader
     rr: RewriteRules,
     max_depth: usize,
-    generated_r: GeneratedFunc,
-    generated_g: GeneratedFunc,
-    generated_b: GeneratedFunc,
+    /// fold constants in generated functions
+    simplify: bool,
+    codegen_opts: CodegenOpts,
+    generated: GeneratedFuncs,
+    generated_lets: String,
+    /// template variables to generate
+    slots: Vec<GeneratedFunc>,
     feedback: LogsView,
     gl: Arc<glow::Context>,
     gl_viewport: Arc<Mutex<ViewportQuad>>,
@@ -106,10 +111,10 @@ struct ShaderGen {
     t_max: f64,
     play: bool,
     advancing: bool,
+    /// project file picked for loading, see `files::load`
+    loaded: files::Loaded,
 }
 
-static DEFAULT_GRAMMAR: &str = include_str!("../grammar.bnf");
-static DEFAULT_FRAG: &str = include_str!("../default_frag.glsl");
 const ASPECT: f32 = 9.0 / 16.0;
 
 impl ShaderGen {
@@ -120,19 +125,30 @@ impl ShaderGen {
         let grammar = CodeEdit::new(gcode, "".to_string());
         let fcode = DEFAULT_FRAG.to_string();
         let frag = CodeEdit::new(fcode, "c".to_string()); // not c, but it will have to do...
-        let rr = parse_rewrite_rules(DEFAULT_GRAMMAR).unwrap();
+        let funcs = FunctionRegistry::from_shader(DEFAULT_FRAG);
+        let rr = parse_rewrite_rules_with(DEFAULT_GRAMMAR, funcs).unwrap();
         let gl = cc.gl.as_ref().unwrap().clone();
         let next_seed = SRNG.write().unwrap().random();
+        let slots = Slot::defaults(&rr, 10)
+            .into_iter()
+            .map(GeneratedFunc::new)
+            .collect();
         let mut new = Self {
             grammar,
             frag,
+            rendered: CodeEdit::new(String::new(), "c".to_string()),
             rr,
             max_depth: 10,
-            generated_r: Default::default(),
-            generated_g: Default::default(),
-            generated_b: Default::default(),
+            simplify: true,
+            codegen_opts: Default::default(),
+            generated: GeneratedFuncs {
+                lets: vec![],
+                slots: vec![],
+            },
+            generated_lets: Default::default(),
+            slots,
             feedback: Default::default(),
-            gl_viewport: Arc::new(Mutex::new(ViewportQuad::new(&gl, DEFAULT_FRAG))),
+            gl_viewport: Arc::new(Mutex::new(ViewportQuad::new(&gl))),
             gl,
             next_seed_str: format!("{next_seed}"),
             next_seed,
@@ -141,51 +157,223 @@ impl ShaderGen {
             t_max: 10.0,
             play: true,
             advancing: true,
+            loaded: Default::default(),
         };
         new.generate_funcs();
-        new.insert_channel_funcs();
+        new.render_shader();
         new.compile_shader();
         new
     }
-    fn _insert_channel_funcs(&mut self) -> Option<()> {
-        let rdec = find_var_decl_line(&self.frag.code, "red")?;
-        let gdec = find_var_decl_line(&self.frag.code, "green")?;
-        let bdec = find_var_decl_line(&self.frag.code, "blue")?;
-        let mut lines: Vec<_> = self.frag.code.lines().collect();
-        let rline = format!("    float red = {};", &self.generated_r.generated_str);
-        let gline = format!("    float green = {};", &self.generated_g.generated_str);
-        let bline = format!("    float blue = {};", &self.generated_b.generated_str);
-        lines[rdec] = rline.as_str();
-        lines[gdec] = gline.as_str();
-        lines[bdec] = bline.as_str();
-        self.frag.code = lines.join("\n");
-        Some(())
-    }
     fn generate_funcs(&mut self) {
         self.last_seed = self.next_seed;
-        *RNG.write().unwrap() = ChaCha8Rng::seed_from_u64(self.next_seed);
-        self.generated_r.regen(&self.rr, self.max_depth);
-        self.generated_g.regen(&self.rr, self.max_depth);
-        self.generated_b.regen(&self.rr, self.max_depth);
+        let slots: Vec<_> = self
+            .slots
+            .iter()
+            .map(|g| g.slot.clone())
+            .filter(|slot| match slot.check(&self.rr) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Slot {} skipped, {e:?}", slot.name);
+                    false
+                }
+            })
+            .collect();
+        self.generated = gen_slots(&self.rr, self.next_seed, self.max_depth, &slots);
+        if self.simplify {
+            self.generated.simplify(&self.rr.funcs);
+        }
+        self.show_generated_strs();
         // advance seed for next time
         self.next_seed = SRNG.write().unwrap().random();
         self.next_seed_str = format!("{}", self.next_seed);
     }
-    fn insert_channel_funcs(&mut self) {
-        if self._insert_channel_funcs().is_none() {
-            warn!("{}", RGB_DECL_WARN);
+    /// fills in the (editable) strings of the generated functions
+    fn show_generated_strs(&mut self) {
+        let lets: Vec<_> = self
+            .generated
+            .lets
+            .iter()
+            .map(|b| format!("{} = {};", b.name, b.expr.as_string()))
+            .collect();
+        self.generated_lets = lets.join("\n");
+        for g in self.slots.iter_mut() {
+            g.generated_str = match self.generated.slot(&g.slot.name) {
+                Some(slot) => slot.expr.as_string(),
+                None => String::new(),
+            };
+        }
+    }
+    fn project(&self) -> Project {
+        Project {
+            version: VERSION,
+            grammar: self.grammar.code.clone(),
+            frag: self.frag.code.clone(),
+            next_seed: self.next_seed,
+            last_seed: self.last_seed,
+            max_depth: self.max_depth,
+            t_max: self.t_max,
+            simplify: self.simplify,
+            codegen_opts: self.codegen_opts,
+            slots: self.slots.iter().map(|g| g.slot.clone()).collect(),
+            generated: self.generated.clone(),
+        }
+    }
+    fn save_project(&self) {
+        match self.project().to_json() {
+            Ok(json) => files::save("shadergen_project.json", json),
+            Err(e) => error!("Could not serialise project: {e}"),
+        }
+    }
+    /// restores a saved session, keeping its functions rather than generating new ones
+    fn load_project(&mut self, src: &str) {
+        let loaded = Project::from_json(src).and_then(|mut project| {
+            let rr = project.check()?;
+            Ok((project, rr))
+        });
+        let (project, rr) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("Could not load project: {e:?}");
+                return;
+            }
+        };
+        self.grammar.code = project.grammar;
+        self.frag.code = project.frag;
+        self.next_seed = project.next_seed;
+        self.next_seed_str = format!("{}", self.next_seed);
+        self.last_seed = project.last_seed;
+        self.max_depth = project.max_depth;
+        self.t_max = project.t_max;
+        self.simplify = project.simplify;
+        self.codegen_opts = project.codegen_opts;
+        for w in rr.warnings.iter() {
+            log::warn!("Grammar warning {}", w.report(&self.grammar.code));
+        }
+        self.rr = rr;
+        self.slots = project.slots.into_iter().map(GeneratedFunc::new).collect();
+        self.generated = project.generated;
+        self.show_generated_strs();
+        self.render_shader();
+        self.compile_shader();
+        log::info!("Loaded project.");
+    }
+    /// Parses the (possibly edited) generated functions back, to render them instead. Bindings
+    /// are written as `name = expr;`.
+    fn apply_edits(&mut self) {
+        let mut lets = vec![];
+        for stmt in self
+            .generated_lets
+            .split(';')
+            .filter(|s| !s.trim().is_empty())
+        {
+            match stmt.split_once('=') {
+                Some((name, expr)) => lets.push((name.trim().to_string(), expr.to_string())),
+                None => {
+                    error!("Expected a binding like a0 = expr; got {}", stmt.trim());
+                    return;
+                }
+            }
+        }
+        let slots: Vec<_> = self
+            .slots
+            .iter()
+            .filter(|g| !g.generated_str.is_empty())
+            .map(|g| (g.slot.name.clone(), g.generated_str.clone()))
+            .collect();
+        match GeneratedFuncs::from_strings(&lets, &slots, &self.rr.funcs) {
+            Ok(generated) => {
+                self.generated = generated;
+                log::info!("Applied edited functions.");
+            }
+            Err((name, e)) => {
+                let src = lets
+                    .iter()
+                    .chain(slots.iter())
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, src)| src.as_str())
+                    .unwrap_or_default();
+                error!("Parse error in {name}: {}", e.report(src));
+            }
+        }
+    }
+    fn render_shader(&mut self) {
+        let names: Vec<_> = self
+            .generated
+            .slots
+            .iter()
+            .map(|b| b.name.as_str())
+            .collect();
+        for name in unused_slots(&self.frag.code, &names) {
+            warn!("The template has no {{{{{name}}}}} placeholder for slot {name}");
+        }
+        match self
+            .generated
+            .render(&self.frag.code, &self.rr.funcs, self.codegen_opts)
+        {
+            Ok(code) => self.rendered.code = code,
+            Err(e) => warn!("Template error {e:?}"),
+        }
+    }
+    /// the channels or colour, as the grammar's entries give
+    fn reset_slots(&mut self) {
+        self.slots = Slot::defaults(&self.rr, self.max_depth)
+            .into_iter()
+            .map(GeneratedFunc::new)
+            .collect();
+    }
+    fn parse_grammar(&mut self) {
+        let funcs = FunctionRegistry::from_shader(&self.frag.code);
+        match parse_rewrite_rules_with(&self.grammar.code, funcs) {
+            Ok(rr) => {
+                for w in rr.warnings.iter() {
+                    log::warn!("Grammar warning {}", w.report(&self.grammar.code));
+                }
+                // NOTE: slots follow the grammar's entries unless they have been edited
+                let defaults = |rr: &RewriteRules| -> Vec<_> {
+                    Slot::defaults(rr, 0)
+                        .into_iter()
+                        .map(|s| (s.name, s.entry))
+                        .collect()
+                };
+                let current: Vec<_> = self
+                    .slots
+                    .iter()
+                    .map(|g| (g.slot.name.clone(), g.slot.entry.clone()))
+                    .collect();
+                let follow = current == defaults(&self.rr);
+                self.rr = rr;
+                if follow {
+                    self.reset_slots();
+                }
+                log::info!("Succesfully parsed grammar.");
+            }
+            Err(e) => {
+                log::error!("Parse error {}", e.report(&self.grammar.code));
+            }
         }
     }
     fn compile_shader(&mut self) {
+        if !FunctionRegistry::from_shader(&self.frag.code).same_signatures(&self.rr.funcs) {
+            log::info!("Functions defined in shader changed, re-parsing grammar.");
+            self.parse_grammar();
+        }
         if let Err(e) = self
             .gl_viewport
             .lock()
             .unwrap()
-            .set_frag_shader(&self.gl, &self.frag.code)
+            .set_frag_shader(&self.gl, &self.rendered.code)
         {
             error!("Failed to compile frag shader: {e}");
         }
     }
+    /// lists the functions grammars may call, as found in the fragment shader
+    fn inspect_funcs(&self, ui: &mut egui::Ui) {
+        ui.collapsing("Grammar functions", |ui| {
+            for (name, def) in self.rr.funcs.iter() {
+                ui.label(def.signature(name));
+            }
+        });
+    }
     fn paint_viewport(&self, ui: &mut egui::Ui) {
         let available = ui.available_size();
         let size = match available.y / ASPECT < available.x {
@@ -220,10 +408,12 @@ impl ShaderGen {
     }
 }
 
-static RGB_DECL_WARN: &str = "Inserting functions into shader failed, please keep the formatting of the r,g,b declarations similar to the default shader (no additional spacing between tokens, each kept on one line, not declared twice, even in other functions).";
-
 impl eframe::App for ShaderGen {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let loaded = self.loaded.lock().unwrap().take();
+        if let Some(src) = loaded {
+            self.load_project(&src);
+        }
         CentralPanel::default().show(ctx, |ui| {
             ui.ctx().request_repaint();
             if self.play {
@@ -245,7 +435,7 @@ impl eframe::App for ShaderGen {
             ui.horizontal_wrapped(|ui| {
                 if ui.button("generate, insert and compile").clicked() {
                     self.generate_funcs();
-                    self.insert_channel_funcs();
+                    self.render_shader();
                     self.compile_shader();
                 }
                 self.play.inspect_mut("play", ui);
@@ -268,6 +458,12 @@ impl eframe::App for ShaderGen {
                 }
 
                 ui.label(format!("last seed: {}", self.last_seed));
+                if ui.button("save project").clicked() {
+                    self.save_project();
+                }
+                if ui.button("load project").clicked() {
+                    files::load(&self.loaded);
+                }
             });
             self.paint_viewport(ui);
         });
@@ -279,38 +475,65 @@ impl eframe::App for ShaderGen {
             // width
             ui.horizontal(|ui| {
                 if ui.button("parse grammar").clicked() {
-                    match parse_rewrite_rules(&self.grammar.code) {
-                        Ok(rr) => {
-                            self.rr = rr;
-                            log::info!("Succesfully parsed grammar.");
-                        }
-                        Err(e) => {
-                            log::error!("Parse error: {e:?}");
-                        }
-                    }
+                    self.parse_grammar();
                 }
             });
+            self.inspect_funcs(ui);
             ui.horizontal(|ui| {
                 self.max_depth
                     .inspect_with_slider("max_depth", ui, 5.0, 25.0);
-                if ui.button("generate channel functions").clicked() {
+                self.simplify.inspect_mut("simplify", ui);
+                if ui.button("generate slot functions").clicked() {
                     self.generate_funcs();
                 }
             });
-            self.generated_r.inspect("r", ui);
-            self.generated_g.inspect("g", ui);
-            self.generated_b.inspect("b", ui);
+            edit_generated(ui, "lets", &mut self.generated_lets, 50.0);
+            ui.label("Slots:");
+            let mut remove = None;
+            for (i, g) in self.slots.iter_mut().enumerate() {
+                ui.push_id(i, |ui| {
+                    if g.inspect_row(&format!("slot {i}"), ui) {
+                        remove = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = remove {
+                self.slots.remove(i);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("apply edited functions").clicked() {
+                    self.apply_edits();
+                    self.render_shader();
+                }
+                if ui.button("add slot").clicked() {
+                    let entry = self.rr.entry_point.clone();
+                    self.slots.push(GeneratedFunc::new(Slot {
+                        name: format!("slot{}", self.slots.len()),
+                        entry,
+                        max_depth: self.max_depth,
+                    }));
+                }
+                if ui.button("reset slots from grammar").clicked() {
+                    self.reset_slots();
+                }
+            });
 
             ui.horizontal(|ui| {
-                if ui.button("insert funcs into shader code").clicked() {
-                    self.insert_channel_funcs();
+                if ui.button("fill in shader template").clicked() {
+                    self.render_shader();
                 }
+                self.codegen_opts
+                    .cse
+                    .inspect_mut("share repeated subexpressions", ui);
                 if ui.button("reset shader code to default").clicked() {
                     self.frag.code = DEFAULT_FRAG.to_string();
                 }
             });
 
-            self.frag.inspect_mut("Fragment: ", ui);
+            self.frag.inspect_mut("Fragment template: ", ui);
+            ui.collapsing("Rendered fragment shader", |ui| {
+                self.rendered.inspect_mut("Rendered: ", ui);
+            });
 
             ui.horizontal(|ui| {
                 if ui.button("compile shader").clicked() {
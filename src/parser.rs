@@ -1,77 +1,73 @@
 //! Likely a fairly naive parser, but it does the job.
 
-use lazy_static::lazy_static;
 use std::collections::{HashMap, HashSet};
 
-use crate::tokeniser::{GTokenError, GTokenKind, TokenSpan, TokenStream};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+use crate::codegen::{HELPER_PREFIX, TEMP_PREFIX};
+use crate::funcgen::rounded_range;
+use crate::functions::{FuncDef, FunctionRegistry};
+use crate::tokeniser::{highlight_span, GTokenError, GTokenKind, TokenSpan, TokenStream};
+use crate::types::Type;
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RewriteRule {
     pub branches: Vec<Branch>,
-    /// indeces of branches that replace with a purely terminal rule, we can restrict the choice
-    /// to these when we need to cap the depth
-    pub terminal_branches: Vec<usize>,
-    /// all branches of this rule are terminal
-    pub purely_terminal: bool,
+    /// type of the expressions this rule expands to, float unless prefixed, e.g. `vec2 P | uv ;`
+    pub ty: Type,
+    /// depth of the shallowest expression this rule can expand to, usize::MAX if it can never be
+    /// fully expanded, see `compute_min_depths`
+    pub min_depth: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Branch {
     pub weight: u8,
     pub expr: Expression,
+    /// depth of the shallowest expression this branch can expand to
+    pub min_depth: usize,
 }
 
-// TODO: Would be more efficient to store the AST in an arena, though may not really matter
-// in this simple case
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Expression {
     Terminal(Term),
-    Func1 {
-        ident: String,
-        args: [Box<Expression>; 1],
-    },
-    Func2 {
-        ident: String,
-        args: [Box<Expression>; 2],
-    },
-    Func3 {
+    Func {
         ident: String,
-        args: [Box<Expression>; 3],
+        args: Vec<Expression>,
     },
     ToBeReplaced {
         rule: String,
     },
+    /// The last argument (the body) evaluated with u and v (and so r and uv) replaced by the
+    /// preceding ones, written as `warp(X, Y, E)` or `warp(P, E)` for a vec2 P.
+    Warp {
+        args: Vec<Expression>,
+        /// of the body
+        ty: Type,
+    },
 }
 
 impl Expression {
-    fn get_replace_rule(&self) -> String {
+    pub fn args(&self) -> &[Expression] {
         match self {
-            Expression::ToBeReplaced { rule } => rule.clone(),
-            _ => panic!("This method can only be used on replace variants."),
+            Expression::Func { args, .. } | Expression::Warp { args, .. } => args,
+            Expression::Terminal(..) | Expression::ToBeReplaced { .. } => &[],
         }
     }
 }
 
-// TODO: runtime registration of new funcs? (since they can be typed into the shader editor)
-lazy_static! {
-    static ref FUNCTION_WHITELIST: HashMap<&'static str, usize> = [
-        ("abs", 1),
-        ("exp", 1),
-        ("sqrt", 1),
-        ("sin", 1),
-        ("add", 2),
-        ("mult", 2),
-        ("sig", 3)
-    ]
-    .into_iter()
-    .collect();
-}
+/// the channels generated functions are made for
+pub const CHANNELS: [&str; 3] = ["red", "green", "blue"];
+/// generates all channels as one vec3 function instead, when given an entry
+pub const COLOR: &str = "color";
+/// not a function, see `Expression::Warp`
+pub const WARP: &str = "warp";
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Term {
-    // TODO: allow for specifying random number range in grammar, perhaps with square brackets?
-    RandConst,
+    /// written as `random` (in [-1,1], to 2 decimals), `random[min, max]` or
+    /// `random[min, max, decimals]`
+    RandConst { min: f32, max: f32, decimals: u8 },
     /// horizontal parameter ranging in [0,1]
     U,
     /// vertical parameter ranging in [0,1]
@@ -80,20 +76,37 @@ pub enum Term {
     T,
     /// radius from screen center, i.e. sqrt(u^2 + v^2)
     R,
-    // TODO: add a numeric literal variant? (requires tokeniser change)
+    /// vec2(u, v)
+    UV,
+    /// a fixed value, either written in the grammar or a resolved random constant
+    Literal(f32),
+    /// a value bound with `@let name = Rule;`
+    Var { name: String, ty: Type },
 }
 
 impl Term {
     fn from_str(ident: &str) -> Option<Self> {
         match ident {
-            "rand" | "random" => Some(Self::RandConst),
+            "rand" | "random" => Some(Self::RandConst {
+                min: -1.0,
+                max: 1.0,
+                decimals: 2,
+            }),
             "u" => Some(Self::U),
             "v" => Some(Self::V),
             "t" => Some(Self::T),
             "r" => Some(Self::R),
+            "uv" => Some(Self::UV),
             _ => None,
         }
     }
+    pub fn ty(&self) -> Type {
+        match self {
+            Term::UV => Type::Vec2,
+            Term::Var { ty, .. } => *ty,
+            _ => Type::Float,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -104,17 +117,59 @@ pub enum ParseFail {
     UnterminatedRule,
     EmptyExpression,
     ExpectedIdentifier,
-    UnsupportedNumberOfFunctionArgs(usize),
     WrongNumberOfFunctionArgs {
         func: String,
         expected: usize,
         got: usize,
     },
-    FunctionNotWhitelisted(String),
+    /// not in the function registry the grammar is parsed with
+    FunctionNotRegistered(String),
+    /// not representable as a (finite) f32
+    InvalidNumber(String),
+    /// expected `random[min, max]` or `random[min, max, decimals]`, with min <= max and at most
+    /// MAX_DECIMALS decimals
+    BadRandomSpec,
     NoRulesFound,
-    /// required to be able to limit depth
-    NoTerminalReplacementInChannelRule,
+    /// the entry point can never be fully expanded (without rule references remaining)
+    UnproductiveEntryPoint(String),
     ExpectedBars,
+    UndefinedRule(String),
+    UnknownDirective(String),
+    ExpectedEquals,
+    /// not a parameter of the function being declared
+    UnknownVariable(String),
+    /// not allowed in a function body
+    UnexpectedToken,
+    /// GLSL builtins cannot be redeclared
+    BuiltinRedeclared(String),
+    /// the shader defines a function of this name already (see `FunctionRegistry::from_shader`),
+    /// which would be called instead
+    ShaderFuncRedeclared(String),
+    /// not one of CHANNELS or COLOR
+    UnknownChannel(String),
+    TypeMismatch {
+        expected: Type,
+        got: Type,
+    },
+    /// already used by the shader, or by a rule, function or binding (for bindings) or a binding
+    /// (for functions)
+    NameTaken(String),
+    /// reserved by GLSL, see `is_reserved`
+    ReservedName(String),
+    /// a binding can only use the ones declared before it, which also rules out cycles
+    LetUsesLaterLet {
+        binding: String,
+        uses: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum ParseWarn {
+    /// cannot be reached from the channels' entry rules or the bindings, so will never be used
+    /// (NOTE: unless extra slots start from it, which the grammar does not know about)
+    UnreachableRule(String),
+    /// can never be fully expanded, so will never be used
+    UnproductiveRule(String),
 }
 
 impl From<GTokenError> for ParseFail {
@@ -123,16 +178,93 @@ impl From<GTokenError> for ParseFail {
     }
 }
 
-type PResult<T> = Result<T, ParseFail>;
+impl ParseFail {
+    fn at(self, span: TokenSpan) -> ParseError {
+        Diagnostic {
+            kind: self,
+            span: Some(span),
+        }
+    }
+}
+
+/// Something found while parsing, along with where in the source it was found.
+#[derive(Debug)]
+pub struct Diagnostic<K> {
+    pub kind: K,
+    /// location of the offending token, if there is one
+    pub span: Option<TokenSpan>,
+}
+
+pub type ParseError = Diagnostic<ParseFail>;
+pub type ParseWarning = Diagnostic<ParseWarn>;
+
+impl From<ParseFail> for ParseError {
+    fn from(kind: ParseFail) -> Self {
+        Self { kind, span: None }
+    }
+}
+
+impl<K: std::fmt::Debug> Diagnostic<K> {
+    /// Describes the problem along with its (1-based) line and column, and the source line with
+    /// the offending token marked.
+    pub fn report(&self, src: &str) -> String {
+        match self.span {
+            Some(span) => format!(
+                "{:?} at line {}, column {}:\n{}",
+                self.kind,
+                span.line + 1,
+                span.startchar + 1,
+                highlight_span(src, span, '^')
+            ),
+            None => format!("{:?}", self.kind),
+        }
+    }
+}
+
+type PResult<T> = Result<T, ParseError>;
+
+/// beyond this f32 constants would not be accurate anyway
+const MAX_DECIMALS: u8 = 6;
 
 #[derive(Debug)]
 struct GToken {
     kind: GTokenKind,
-    // TODO: pass through error locations on ParseFail
-    #[allow(dead_code)]
     span: TokenSpan,
 }
 
+/// state gathered while parsing
+struct ParseCtx {
+    /// rules referenced by the rule currently being parsed
+    refs: Vec<(String, TokenSpan)>,
+    /// functions that may be called
+    funcs: FunctionRegistry,
+    /// channels with their entry rules, as set by directives
+    entries: HashMap<String, (String, TokenSpan)>,
+    /// types of all rules, known ahead of parsing them
+    rule_types: HashMap<String, Type>,
+    /// bindings declared so far, see `parse_let_directive`
+    lets: Vec<LetDef>,
+    /// bindings used by the rule currently being parsed
+    var_refs: Vec<String>,
+}
+
+/// a binding along with the rule it is generated from
+struct LetDef {
+    name: String,
+    rule: String,
+    ty: Type,
+    span: TokenSpan,
+}
+
+/// where a rule was defined and which rules it references
+struct RuleDef {
+    ident: String,
+    span: TokenSpan,
+    refs: Vec<(String, TokenSpan)>,
+    /// bindings
+    var_refs: Vec<String>,
+}
+
 #[allow(dead_code)]
 fn dbg_toks(toks: &[GToken]) {
     for tok in toks {
@@ -141,104 +273,978 @@ fn dbg_toks(toks: &[GToken]) {
     println!();
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RewriteRules {
+    #[serde(serialize_with = "crate::json::sorted")]
     pub rules: HashMap<String, RewriteRule>,
+    /// the first float rule (or the first rule if there is none), used for channels without an
+    /// entry of their own
     pub entry_point: String,
+    /// rules channels start from, set with `@entry channel = Rule;`
+    #[serde(serialize_with = "crate::json::sorted")]
+    pub entries: HashMap<String, String>,
+    /// bindings with the rules they are generated from, set with `@let name = Rule;` (in order of
+    /// declaration, each only using those before it)
+    pub lets: Vec<(String, String)>,
+    /// things that did not stop parsing, but that are likely mistakes (not serialised)
+    #[serde(skip)]
+    pub warnings: Vec<ParseWarning>,
+    /// the functions the grammar was parsed with, needed to define and evaluate calls
+    pub funcs: FunctionRegistry,
+}
+
+impl RewriteRules {
+    /// the rule a channel starts from
+    pub fn entry(&self, channel: &str) -> &str {
+        self.entries.get(channel).unwrap_or(&self.entry_point)
+    }
+    /// the vec3 rule to generate all channels from at once, if any
+    pub fn color_entry(&self) -> Option<&str> {
+        self.entries.get(COLOR).map(|rule| rule.as_str())
+    }
 }
 
+/// Parses a grammar that may call the builtin functions.
 pub fn parse_rewrite_rules(src: &str) -> PResult<RewriteRules> {
-    let mut ts = TokenStream::new(src.chars()).peekable();
+    parse_rewrite_rules_with(src, FunctionRegistry::builtin())
+}
 
+/// Parses a grammar that may call any of the given functions.
+pub fn parse_rewrite_rules_with(src: &str, funcs: FunctionRegistry) -> PResult<RewriteRules> {
+    // statements, each terminated by `;`
+    let mut stmts = vec![];
+    let mut toks: Vec<GToken> = vec![];
+    for (res, span) in TokenStream::new(src.chars()) {
+        match res {
+            Ok(GTokenKind::Colon) if toks.is_empty() => {
+                return Err(ParseFail::ExpectedIdentifier.at(span))
+            }
+            Ok(GTokenKind::Colon) => stmts.push(std::mem::take(&mut toks)),
+            Ok(kind) => toks.push(GToken { kind, span }),
+            Err(e) => return Err(ParseFail::TokeniserErr(e).at(span)),
+        }
+    }
+    if let Some(tok) = toks.last() {
+        return Err(ParseFail::UnterminatedRule.at(tok.span));
+    }
+
+    // NOTE: rules may be referenced before they are defined, so their types are gathered first
+    let rule_types = stmts
+        .iter()
+        .filter(|toks| toks[0].kind != GTokenKind::At)
+        .filter_map(|toks| match split_type_prefix(toks) {
+            (
+                ty,
+                [GToken {
+                    kind: GTokenKind::Ident { name },
+                    ..
+                }, ..],
+            ) => Some((name.clone(), ty)),
+            _ => None,
+        })
+        .collect();
+    let mut ctx = ParseCtx {
+        refs: vec![],
+        lets: vec![],
+        var_refs: vec![],
+        funcs,
+        entries: HashMap::new(),
+        rule_types,
+    };
     let mut rules = HashMap::new();
     let mut entry_point = None;
-    let mut purely_terminal = HashSet::new();
-    let mut toks = vec![];
-    loop {
-        loop {
-            match ts.next() {
-                None => return Err(ParseFail::UnterminatedRule),
-                Some((Ok(kind), span)) => match kind {
-                    GTokenKind::Colon => break,
-                    _ => toks.push(GToken { kind, span }),
-                },
-                Some((Err(e), _)) => return Err(ParseFail::TokeniserErr(e)),
-            };
+    // in order of definition
+    let mut defined = vec![];
+    for toks in stmts.iter() {
+        if toks[0].kind == GTokenKind::At {
+            parse_directive(&mut ctx, toks)?;
+            continue;
         }
-        let (ident, rule) = parse_rewrite_rule(&toks)?;
+        let (ty, toks) = split_type_prefix(toks);
+        let (ident, rule) = parse_rewrite_rule(&mut ctx, toks, ty)?;
 
-        if entry_point.is_none() {
-            // NOTE: First rule becomes the color channel rule
-            entry_point = Some(ident.clone());
+        // NOTE: the first float rule becomes the color channel rule (the first rule if there is
+        // none, e.g. with a vec3 entry for the whole colour)
+        let float = rule.ty == Type::Float;
+        match entry_point {
+            None => entry_point = Some((ident.clone(), toks[0].span, float)),
+            Some((_, _, false)) if float => entry_point = Some((ident.clone(), toks[0].span, true)),
+            _ => {}
         }
 
-        if rule.purely_terminal {
-            purely_terminal.insert(ident.clone());
-        }
+        defined.push(RuleDef {
+            ident: ident.clone(),
+            span: toks[0].span,
+            refs: std::mem::take(&mut ctx.refs),
+            var_refs: std::mem::take(&mut ctx.var_refs),
+        });
         rules.insert(ident, rule);
+    }
 
-        if ts.peek().is_none() {
-            break;
+    let (entry_point, entry_span, _) = entry_point.ok_or(ParseFail::NoRulesFound)?;
+    for (rule, span) in ctx.entries.values() {
+        if !rules.contains_key(rule) {
+            return Err(ParseFail::UndefinedRule(rule.clone()).at(*span));
         }
-        toks.clear();
     }
 
-    for rule in rules.values_mut() {
-        let filtered_tb: Vec<_> = rule
-            .terminal_branches
+    compute_min_depths(&mut rules);
+
+    // NOTE: the entry point is only used if some channel falls back to it
+    let roots: Vec<_> = match ctx.entries.get(COLOR) {
+        Some((rule, span)) => vec![(rule.as_str(), *span, Type::Vec3)],
+        None => CHANNELS
             .iter()
-            .filter(|i| {
-                let tr = rule.branches[**i].expr.get_replace_rule();
-                purely_terminal.contains(&tr)
+            .map(|channel| match ctx.entries.get(*channel) {
+                Some((rule, span)) => (rule.as_str(), *span, Type::Float),
+                None => (entry_point.as_str(), entry_span, Type::Float),
             })
-            .cloned()
-            .collect();
-        rule.terminal_branches = filtered_tb;
+            .collect(),
+    };
+    let lets = ctx
+        .lets
+        .iter()
+        .map(|def| (def.rule.as_str(), def.span, rules[&def.rule].ty));
+    let roots: Vec<_> = roots.into_iter().chain(lets).collect();
+    for (rule, span, ty) in roots.iter() {
+        let rule_ = rules.get(*rule).unwrap();
+        if rule_.ty != *ty {
+            return Err(ParseFail::TypeMismatch {
+                expected: *ty,
+                got: rule_.ty,
+            }
+            .at(*span));
+        }
+        if rule_.min_depth == usize::MAX {
+            return Err(ParseFail::UnproductiveEntryPoint(rule.to_string()).at(*span));
+        }
+    }
+    for (i, def) in ctx.lets.iter().enumerate() {
+        let reached = reachable_rules(&defined, &[&def.rule]);
+        let later = defined
+            .iter()
+            .filter(|rule| reached.contains(rule.ident.as_str()))
+            .flat_map(|rule| rule.var_refs.iter())
+            .find(|var| ctx.lets[i..].iter().any(|later| later.name == **var));
+        if let Some(var) = later {
+            return Err(ParseFail::LetUsesLaterLet {
+                binding: def.name.clone(),
+                uses: var.clone(),
+            }
+            .at(def.span));
+        }
+    }
+    let roots: Vec<_> = roots.iter().map(|root| root.0).collect();
+    let mut warnings = unreachable_rules(&defined, &roots);
+    for def in defined.iter() {
+        if rules.get(&def.ident).unwrap().min_depth == usize::MAX {
+            warnings.push(Diagnostic {
+                kind: ParseWarn::UnproductiveRule(def.ident.clone()),
+                span: Some(def.span),
+            });
+        }
     }
+    let entries = ctx
+        .entries
+        .into_iter()
+        .map(|(channel, (rule, _))| (channel, rule))
+        .collect();
+    let lets = ctx
+        .lets
+        .into_iter()
+        .map(|def| (def.name, def.rule))
+        .collect();
+    Ok(RewriteRules {
+        rules,
+        entry_point,
+        entries,
+        lets,
+        warnings,
+        funcs: ctx.funcs,
+    })
+}
 
-    match entry_point {
-        Some(entry_point) => match rules
-            .get(&entry_point)
-            .unwrap()
-            .terminal_branches
-            .is_empty()
-        {
-            true => Err(ParseFail::NoTerminalReplacementInChannelRule),
-            false => Ok(RewriteRules { rules, entry_point }),
+/// Parses a single expression as `Expression::as_string` writes it (e.g. an edited generated
+/// function), along with the type it produces. Calls are checked against `funcs` and `vars` are
+/// the bindings it may use, but there are no rules to refer to. Random constants are left for
+/// `Expression::resolve_rand_consts`.
+pub fn parse_expression(
+    src: &str,
+    funcs: &FunctionRegistry,
+    vars: &[(String, Type)],
+) -> PResult<(Expression, Type)> {
+    let mut toks = vec![];
+    for (res, span) in TokenStream::new(src.chars()) {
+        match res {
+            Ok(kind) => toks.push(GToken { kind, span }),
+            Err(e) => return Err(ParseFail::TokeniserErr(e).at(span)),
+        }
+    }
+    let span = TokenSpan {
+        line: 0,
+        startchar: 0,
+        length: 0,
+    };
+    let mut ctx = ParseCtx {
+        refs: vec![],
+        funcs: funcs.clone(),
+        entries: HashMap::new(),
+        rule_types: HashMap::new(),
+        lets: vars
+            .iter()
+            .map(|(name, ty)| LetDef {
+                name: name.clone(),
+                rule: String::new(),
+                ty: *ty,
+                span,
+            })
+            .collect(),
+        var_refs: vec![],
+    };
+    parse_expr(&mut ctx, &toks)
+}
+
+/// Depth of the shallowest expression a branch can expand to, given the min depths of rules found
+/// so far. Branches that only replace with another rule count as a level themselves, so that
+/// following the shallowest branches always leads to rules of strictly lower min depth.
+fn branch_min_depth(expr: &Expression, min_depths: &HashMap<String, usize>) -> usize {
+    match expr {
+        Expression::ToBeReplaced { rule } => min_depths.get(rule).unwrap().saturating_add(1),
+        _ => expr_min_depth(expr, min_depths),
+    }
+}
+
+fn expr_min_depth(expr: &Expression, min_depths: &HashMap<String, usize>) -> usize {
+    match expr {
+        Expression::Terminal(..) => 1,
+        Expression::ToBeReplaced { rule } => *min_depths.get(rule).unwrap(),
+        _ => expr
+            .args()
+            .iter()
+            .map(|arg| expr_min_depth(arg, min_depths))
+            .max()
+            .unwrap_or(0)
+            .saturating_add(1),
+    }
+}
+
+/// Finds the min depth of every rule as a fixed point: starting with every rule at usize::MAX
+/// (not known to terminate), repeatedly lower each to the shallowest of its branches until nothing
+/// changes. Rules still at usize::MAX after that cannot be fully expanded.
+fn compute_min_depths(rules: &mut HashMap<String, RewriteRule>) {
+    let mut min_depths: HashMap<String, usize> = rules
+        .keys()
+        .map(|ident| (ident.clone(), usize::MAX))
+        .collect();
+    loop {
+        let mut changed = false;
+        for (ident, rule) in rules.iter() {
+            let min_depth = rule
+                .branches
+                .iter()
+                .map(|b| branch_min_depth(&b.expr, &min_depths))
+                .min()
+                .unwrap_or(usize::MAX);
+            if min_depth < *min_depths.get(ident).unwrap() {
+                min_depths.insert(ident.clone(), min_depth);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for (ident, rule) in rules.iter_mut() {
+        for branch in rule.branches.iter_mut() {
+            branch.min_depth = branch_min_depth(&branch.expr, &min_depths);
+        }
+        rule.min_depth = *min_depths.get(ident).unwrap();
+    }
+}
+
+fn reachable_rules<'a>(defined: &'a [RuleDef], roots: &[&'a str]) -> HashSet<&'a str> {
+    let mut reached: HashSet<&str> = roots.iter().cloned().collect();
+    let mut queue = roots.to_vec();
+    while let Some(next) = queue.pop() {
+        // NOTE: a rule may have been defined more than once, the last definition is the one used
+        if let Some(def) = defined.iter().rev().find(|def| def.ident == next) {
+            for (rule, _) in def.refs.iter() {
+                if reached.insert(rule) {
+                    queue.push(rule);
+                }
+            }
+        }
+    }
+    reached
+}
+
+fn unreachable_rules(defined: &[RuleDef], roots: &[&str]) -> Vec<ParseWarning> {
+    let reached = reachable_rules(defined, roots);
+    defined
+        .iter()
+        .filter(|def| !reached.contains(def.ident.as_str()))
+        .map(|def| Diagnostic {
+            kind: ParseWarn::UnreachableRule(def.ident.clone()),
+            span: Some(def.span),
+        })
+        .collect()
+}
+
+/// Parses a directive, one of
+/// - `@func wave(x, f) = sin(x*f);` declares a function, which gets added to the function registry
+///   along with its GLSL definition. Functions need declaring before the rules using them.
+/// - `@entry red = R;` makes a channel start from a rule other than the first one.
+/// - `@let a0 = C;` binds a value generated from a rule, that rules declared after it can use
+///   (generated once per function, so shared by all channels).
+fn parse_directive(ctx: &mut ParseCtx, toks: &[GToken]) -> PResult<()> {
+    let n = toks.len();
+    match toks.get(1).map(|tok| &tok.kind) {
+        Some(GTokenKind::Ident { name }) if name == "func" => parse_func_directive(ctx, toks),
+        Some(GTokenKind::Ident { name }) if name == "entry" => parse_entry_directive(ctx, toks),
+        Some(GTokenKind::Ident { name }) if name == "let" => parse_let_directive(ctx, toks),
+        Some(GTokenKind::Ident { name }) => {
+            Err(ParseFail::UnknownDirective(name.clone()).at(toks[1].span))
+        }
+        _ => Err(ParseFail::ExpectedIdentifier.at(toks[n.min(2) - 1].span)),
+    }
+}
+
+fn parse_entry_directive(ctx: &mut ParseCtx, toks: &[GToken]) -> PResult<()> {
+    let n = toks.len();
+    let channel = match toks.get(2).map(|tok| &tok.kind) {
+        Some(GTokenKind::Ident { name }) if CHANNELS.contains(&name.as_str()) || name == COLOR => {
+            name.clone()
+        }
+        Some(GTokenKind::Ident { name }) => {
+            return Err(ParseFail::UnknownChannel(name.clone()).at(toks[2].span))
+        }
+        _ => return Err(ParseFail::ExpectedIdentifier.at(toks[n.min(3) - 1].span)),
+    };
+    if toks.get(3).map(|tok| &tok.kind) != Some(&GTokenKind::Equals) {
+        return Err(ParseFail::ExpectedEquals.at(toks[n.min(4) - 1].span));
+    }
+    match &toks[4..] {
+        [GToken {
+            kind: GTokenKind::Ident { name },
+            span,
+        }] if Term::from_str(name).is_none() => {
+            ctx.entries.insert(channel, (name.clone(), *span));
+            Ok(())
+        }
+        [] => Err(ParseFail::ExpectedIdentifier.at(toks[3].span)),
+        [tok, ..] => Err(ParseFail::ExpectedIdentifier.at(tok.span)),
+    }
+}
+
+/// names the shader uses besides the terms and channels (COLOR doubling as its output)
+const SHADER_NAMES: [&str; 3] = ["rgb", "main", COLOR];
+
+/// GLSL ES 3.00 keywords (section 3.7 of the spec)
+const GLSL_KEYWORDS: &[&str] = &[
+    "attribute",
+    "const",
+    "uniform",
+    "varying",
+    "layout",
+    "centroid",
+    "flat",
+    "smooth",
+    "break",
+    "continue",
+    "do",
+    "for",
+    "while",
+    "switch",
+    "case",
+    "default",
+    "if",
+    "else",
+    "in",
+    "out",
+    "inout",
+    "float",
+    "int",
+    "void",
+    "bool",
+    "true",
+    "false",
+    "invariant",
+    "discard",
+    "return",
+    "mat2",
+    "mat3",
+    "mat4",
+    "mat2x2",
+    "mat2x3",
+    "mat2x4",
+    "mat3x2",
+    "mat3x3",
+    "mat3x4",
+    "mat4x2",
+    "mat4x3",
+    "mat4x4",
+    "vec2",
+    "vec3",
+    "vec4",
+    "ivec2",
+    "ivec3",
+    "ivec4",
+    "bvec2",
+    "bvec3",
+    "bvec4",
+    "uint",
+    "uvec2",
+    "uvec3",
+    "uvec4",
+    "lowp",
+    "mediump",
+    "highp",
+    "precision",
+    "sampler2D",
+    "sampler3D",
+    "samplerCube",
+    "sampler2DShadow",
+    "samplerCubeShadow",
+    "sampler2DArray",
+    "sampler2DArrayShadow",
+    "isampler2D",
+    "isampler3D",
+    "isamplerCube",
+    "isampler2DArray",
+    "usampler2D",
+    "usampler3D",
+    "usamplerCube",
+    "usampler2DArray",
+    "struct",
+];
+
+/// GLSL ES 3.00 words reserved for future use, which are errors to use as well
+const GLSL_RESERVED: &[&str] = &[
+    "coherent",
+    "volatile",
+    "restrict",
+    "readonly",
+    "writeonly",
+    "resource",
+    "atomic_uint",
+    "noperspective",
+    "patch",
+    "sample",
+    "subroutine",
+    "common",
+    "partition",
+    "active",
+    "asm",
+    "class",
+    "union",
+    "enum",
+    "typedef",
+    "template",
+    "this",
+    "goto",
+    "inline",
+    "noinline",
+    "public",
+    "static",
+    "extern",
+    "external",
+    "interface",
+    "long",
+    "short",
+    "double",
+    "half",
+    "fixed",
+    "unsigned",
+    "superp",
+    "input",
+    "output",
+    "hvec2",
+    "hvec3",
+    "hvec4",
+    "dvec2",
+    "dvec3",
+    "dvec4",
+    "fvec2",
+    "fvec3",
+    "fvec4",
+    "sampler3DRect",
+    "filter",
+    "image1D",
+    "image2D",
+    "image3D",
+    "imageCube",
+    "iimage1D",
+    "iimage2D",
+    "iimage3D",
+    "iimageCube",
+    "uimage1D",
+    "uimage2D",
+    "uimage3D",
+    "uimageCube",
+    "image1DArray",
+    "image2DArray",
+    "iimage1DArray",
+    "iimage2DArray",
+    "uimage1DArray",
+    "uimage2DArray",
+    "imageBuffer",
+    "iimageBuffer",
+    "uimageBuffer",
+    "sampler1D",
+    "sampler1DShadow",
+    "sampler1DArray",
+    "sampler1DArrayShadow",
+    "isampler1D",
+    "isampler1DArray",
+    "usampler1D",
+    "usampler1DArray",
+    "sampler2DRect",
+    "sampler2DRectShadow",
+    "isampler2DRect",
+    "usampler2DRect",
+    "samplerBuffer",
+    "isamplerBuffer",
+    "usamplerBuffer",
+    "sampler2DMS",
+    "isampler2DMS",
+    "usampler2DMS",
+    "sampler2DMSArray",
+    "isampler2DMSArray",
+    "usampler2DMSArray",
+    "sizeof",
+    "cast",
+    "namespace",
+    "using",
+];
+
+/// Whether GLSL reserves the name, being a keyword, a reserved word, starting with `gl_` or
+/// containing `__`, so that neither variables nor functions can have it.
+fn is_reserved(name: &str) -> bool {
+    GLSL_KEYWORDS.contains(&name)
+        || GLSL_RESERVED.contains(&name)
+        || name.starts_with("gl_")
+        || name.contains("__")
+}
+
+/// GLSL ES 3.00 builtin functions (chapter 8 of the spec), which cannot be redeclared or
+/// overloaded
+const GLSL_BUILTIN_FUNCS: &[&str] = &[
+    "radians",
+    "degrees",
+    "sin",
+    "cos",
+    "tan",
+    "asin",
+    "acos",
+    "atan",
+    "sinh",
+    "cosh",
+    "tanh",
+    "asinh",
+    "acosh",
+    "atanh",
+    "pow",
+    "exp",
+    "log",
+    "exp2",
+    "log2",
+    "sqrt",
+    "inversesqrt",
+    "abs",
+    "sign",
+    "floor",
+    "trunc",
+    "round",
+    "roundEven",
+    "ceil",
+    "fract",
+    "mod",
+    "min",
+    "max",
+    "clamp",
+    "mix",
+    "step",
+    "smoothstep",
+    "isnan",
+    "isinf",
+    "floatBitsToInt",
+    "floatBitsToUint",
+    "intBitsToFloat",
+    "uintBitsToFloat",
+    "packSnorm2x16",
+    "unpackSnorm2x16",
+    "packUnorm2x16",
+    "unpackUnorm2x16",
+    "packHalf2x16",
+    "unpackHalf2x16",
+    "length",
+    "distance",
+    "dot",
+    "cross",
+    "normalize",
+    "faceforward",
+    "reflect",
+    "refract",
+    "matrixCompMult",
+    "outerProduct",
+    "transpose",
+    "determinant",
+    "inverse",
+    "lessThan",
+    "lessThanEqual",
+    "greaterThan",
+    "greaterThanEqual",
+    "equal",
+    "notEqual",
+    "any",
+    "all",
+    "not",
+    "textureSize",
+    "texture",
+    "textureProj",
+    "textureLod",
+    "textureOffset",
+    "texelFetch",
+    "texelFetchOffset",
+    "textureProjOffset",
+    "textureLodOffset",
+    "textureProjLod",
+    "textureProjLodOffset",
+    "textureGrad",
+    "textureGradOffset",
+    "textureProjGrad",
+    "textureProjGradOffset",
+    "dFdx",
+    "dFdy",
+    "fwidth",
+];
+
+/// Whether the shader or the generated code use the name already, whatever the grammar declares:
+/// terms, channels, other shader names, GLSL builtin functions, warps, helpers and temporaries.
+fn used_by_shader(name: &str) -> bool {
+    Term::from_str(name).is_some()
+        || CHANNELS.contains(&name)
+        || SHADER_NAMES.contains(&name)
+        || GLSL_BUILTIN_FUNCS.contains(&name)
+        || name == WARP
+        || name.starts_with(HELPER_PREFIX)
+        || name.starts_with(TEMP_PREFIX)
+}
+
+fn parse_let_directive(ctx: &mut ParseCtx, toks: &[GToken]) -> PResult<()> {
+    let n = toks.len();
+    let (name, span) = match toks.get(2) {
+        Some(GToken {
+            kind: GTokenKind::Ident { name },
+            span,
+        }) => (name.clone(), *span),
+        _ => return Err(ParseFail::ExpectedIdentifier.at(toks[n.min(3) - 1].span)),
+    };
+    let taken = used_by_shader(&name)
+        || ctx.rule_types.contains_key(&name)
+        || ctx.funcs.get(&name).is_some()
+        || ctx.lets.iter().any(|def| def.name == name);
+    if is_reserved(&name) {
+        return Err(ParseFail::ReservedName(name).at(span));
+    }
+    if taken {
+        return Err(ParseFail::NameTaken(name).at(span));
+    }
+    if toks.get(3).map(|tok| &tok.kind) != Some(&GTokenKind::Equals) {
+        return Err(ParseFail::ExpectedEquals.at(toks[n.min(4) - 1].span));
+    }
+    match &toks[4..] {
+        [GToken {
+            kind: GTokenKind::Ident { name: rule },
+            span: rule_span,
+        }] => {
+            let Some(ty) = ctx.rule_types.get(rule) else {
+                return Err(ParseFail::UndefinedRule(rule.clone()).at(*rule_span));
+            };
+            ctx.lets.push(LetDef {
+                name,
+                rule: rule.clone(),
+                ty: *ty,
+                span,
+            });
+            Ok(())
+        }
+        [] => Err(ParseFail::ExpectedIdentifier.at(toks[3].span)),
+        [tok, ..] => Err(ParseFail::ExpectedIdentifier.at(tok.span)),
+    }
+}
+
+fn parse_func_directive(ctx: &mut ParseCtx, toks: &[GToken]) -> PResult<()> {
+    // NOTE: types are optional, defaulting to float. When given, the leading `@` gets dropped so
+    // that the indices below stay the same either way
+    let (ret, rest) = split_type_prefix(&toks[2..]);
+    let toks = &toks[toks.len() - rest.len() - 2..];
+    let n = toks.len();
+    let name = match toks.get(2).map(|tok| &tok.kind) {
+        Some(GTokenKind::Ident { name }) => name.clone(),
+        _ => return Err(ParseFail::ExpectedIdentifier.at(toks[n.min(3) - 1].span)),
+    };
+    if GLSL_BUILTIN_FUNCS.contains(&name.as_str()) || name == WARP {
+        return Err(ParseFail::BuiltinRedeclared(name).at(toks[2].span));
+    }
+    if is_reserved(&name) {
+        return Err(ParseFail::ReservedName(name).at(toks[2].span));
+    }
+    // NOTE: functions from `@func`s have a GLSL definition, those from the shader do not
+    if ctx.funcs.get(&name).is_some_and(|def| def.glsl.is_none()) {
+        return Err(ParseFail::ShaderFuncRedeclared(name).at(toks[2].span));
+    }
+    // NOTE: bindings are local to main, where they would hide the function
+    if used_by_shader(&name) || ctx.lets.iter().any(|def| def.name == name) {
+        return Err(ParseFail::NameTaken(name).at(toks[2].span));
+    }
+    let close = match toks.get(3).map(|tok| &tok.kind) {
+        Some(GTokenKind::LPar) => toks.iter().position(|tok| tok.kind == GTokenKind::RPar),
+        _ => None,
+    }
+    .ok_or(ParseFail::BadArglist.at(toks[n.min(4) - 1].span))?;
+    let mut params = vec![];
+    let mut param_types = vec![];
+    if close > 4 {
+        // index of the delimiter preceding the current parameter
+        let mut delim = 3;
+        for s in split_arglist(&toks[4..close]) {
+            match split_type_prefix(s) {
+                (
+                    ty,
+                    [GToken {
+                        kind: GTokenKind::Ident { name },
+                        span,
+                    }],
+                ) => {
+                    if is_reserved(name) {
+                        return Err(ParseFail::ReservedName(name.clone()).at(*span));
+                    }
+                    params.push(name.clone());
+                    param_types.push(ty);
+                }
+                (_, []) => return Err(ParseFail::ExpectedIdentifier.at(toks[delim + 1].span)),
+                (_, [tok, ..]) => return Err(ParseFail::ExpectedIdentifier.at(tok.span)),
+            }
+            delim += s.len() + 1;
+        }
+    }
+    match toks.get(close + 1) {
+        Some(GToken {
+            kind: GTokenKind::Equals,
+            ..
+        }) => {}
+        _ => return Err(ParseFail::ExpectedEquals.at(toks[close].span)),
+    }
+    if close + 2 == n {
+        return Err(ParseFail::EmptyExpression.at(toks[close + 1].span));
+    }
+    let params: Vec<_> = params
+        .into_iter()
+        .zip(param_types.iter().copied())
+        .collect();
+    let body_toks = split_signed_numbers(&toks[close + 2..]);
+    let (body, got) = glsl_body(ctx, &params, &body_toks)?;
+    if got != ret {
+        return Err(ParseFail::TypeMismatch { expected: ret, got }.at(toks[close + 2].span));
+    }
+    let decls: Vec<_> = params
+        .iter()
+        .map(|(p, ty)| format!("{} {p}", ty.as_str()))
+        .collect();
+    let glsl = format!(
+        "{} {name}({}) {{\n    return {body};\n}}",
+        ret.as_str(),
+        decls.join(", ")
+    );
+    let mut def = FuncDef::new(param_types, ret);
+    def.glsl = Some(glsl);
+    ctx.funcs.register(name, def);
+    Ok(())
+}
+
+fn is_operator(kind: &GTokenKind) -> bool {
+    matches!(
+        kind,
+        GTokenKind::Plus | GTokenKind::Minus | GTokenKind::Star | GTokenKind::Slash
+    )
+}
+
+/// The tokeniser reads `-1` as a single number, so in `x-1` a negative number following an operand
+/// gets split back into a minus and a positive number.
+fn split_signed_numbers(toks: &[GToken]) -> Vec<GToken> {
+    let mut out: Vec<GToken> = Vec::with_capacity(toks.len());
+    for tok in toks {
+        let after_operand = out.last().is_some_and(|prev| {
+            matches!(
+                prev.kind,
+                GTokenKind::Number { .. } | GTokenKind::Ident { .. } | GTokenKind::RPar
+            )
+        });
+        match &tok.kind {
+            GTokenKind::Number { text } if after_operand && text.starts_with('-') => {
+                out.push(GToken {
+                    kind: GTokenKind::Minus,
+                    span: TokenSpan {
+                        length: 1,
+                        ..tok.span
+                    },
+                });
+                out.push(GToken {
+                    kind: GTokenKind::Number {
+                        text: text[1..].to_string(),
+                    },
+                    span: TokenSpan {
+                        startchar: tok.span.startchar + 1,
+                        length: tok.span.length - 1,
+                        ..tok.span
+                    },
+                });
+            }
+            kind => out.push(GToken {
+                kind: kind.clone(),
+                span: tok.span,
+            }),
+        }
+    }
+    out
+}
+
+/// Rebuilds a function body as a GLSL expression (with numbers written as GLSL floats), along
+/// with its type. Calls are checked as in rules, and `+`, `-`, `*` and `/` take operands of the
+/// same type, or one of them a float.
+fn glsl_body(
+    ctx: &ParseCtx,
+    params: &[(String, Type)],
+    toks: &[GToken],
+) -> PResult<(String, Type)> {
+    let Some(first) = toks.first() else {
+        return Err(ParseFail::EmptyExpression.into());
+    };
+    // the last binary operator outside parentheses splits the body, lowest precedence first
+    for ops in [
+        [GTokenKind::Plus, GTokenKind::Minus],
+        [GTokenKind::Star, GTokenKind::Slash],
+    ] {
+        let mut level = 0;
+        let mut split = None;
+        for (i, tok) in toks.iter().enumerate() {
+            match tok.kind {
+                GTokenKind::LPar => level += 1,
+                GTokenKind::RPar => level -= 1,
+                _ => {}
+            }
+            // NOTE: anything else is a unary minus
+            let binary =
+                i > 0 && !is_operator(&toks[i - 1].kind) && toks[i - 1].kind != GTokenKind::LPar;
+            if level == 0 && binary && ops.contains(&tok.kind) {
+                split = Some(i);
+            }
+        }
+        let Some(i) = split else {
+            continue;
+        };
+        if i + 1 == toks.len() {
+            return Err(ParseFail::EmptyExpression.at(toks[i].span));
+        }
+        let (lhs, lty) = glsl_body(ctx, params, &toks[..i])?;
+        let (rhs, rty) = glsl_body(ctx, params, &toks[i + 1..])?;
+        let ty = match (lty, rty) {
+            (a, b) if a == b => a,
+            (Type::Float, ty) | (ty, Type::Float) => ty,
+            (expected, got) => {
+                return Err(ParseFail::TypeMismatch { expected, got }.at(toks[i + 1].span))
+            }
+        };
+        return Ok((format!("{lhs} {} {rhs}", toks[i].kind.as_str()), ty));
+    }
+    let n = toks.len();
+    match &first.kind {
+        GTokenKind::Minus if n == 1 => Err(ParseFail::EmptyExpression.at(first.span)),
+        GTokenKind::Minus => {
+            let (body, ty) = glsl_body(ctx, params, &toks[1..])?;
+            Ok((format!("-{body}"), ty))
+        }
+        GTokenKind::LPar if n > 2 && toks[n - 1].kind == GTokenKind::RPar => {
+            let (body, ty) = glsl_body(ctx, params, &toks[1..n - 1])?;
+            Ok((format!("({body})"), ty))
+        }
+        GTokenKind::Number { text } if n == 1 => match text.parse::<f32>() {
+            Ok(x) if x.is_finite() => Ok((format!("{x:?}"), Type::Float)),
+            _ => Err(ParseFail::InvalidNumber(text.clone()).at(first.span)),
         },
-        None => Err(ParseFail::NoRulesFound),
+        GTokenKind::Ident { name } if n == 1 => match params.iter().find(|(p, _)| p == name) {
+            Some((_, ty)) => Ok((name.clone(), *ty)),
+            None => Err(ParseFail::UnknownVariable(name.clone()).at(first.span)),
+        },
+        GTokenKind::Ident { name }
+            if toks[1].kind == GTokenKind::LPar && toks[n - 1].kind == GTokenKind::RPar =>
+        {
+            let argss = match n {
+                3 => vec![],
+                _ => split_arglist(&toks[2..n - 1]),
+            };
+            let mut args = vec![];
+            let mut arg_types = vec![];
+            let mut arg_spans = vec![];
+            for s in argss {
+                let (arg, ty) = glsl_body(ctx, params, s)?;
+                args.push(arg);
+                arg_types.push(ty);
+                arg_spans.push(s[0].span);
+            }
+            let ty = check_call(ctx, name, &arg_types, &arg_spans, first.span)?;
+            Ok((format!("{name}({})", args.join(", ")), ty))
+        }
+        _ => Err(ParseFail::UnexpectedToken.at(first.span)),
+    }
+}
+
+/// splits off the type of a rule, if given
+fn split_type_prefix(toks: &[GToken]) -> (Type, &[GToken]) {
+    let ty = match toks {
+        [GToken {
+            kind: GTokenKind::Ident { name },
+            ..
+        }, GToken {
+            kind: GTokenKind::Ident { .. },
+            ..
+        }, ..] => Type::from_name(name),
+        _ => None,
+    };
+    match ty {
+        Some(ty) => (ty, &toks[1..]),
+        None => (Type::Float, toks),
     }
 }
 
-fn parse_rewrite_rule(toks: &[GToken]) -> PResult<(String, RewriteRule)> {
+fn parse_rewrite_rule(
+    ctx: &mut ParseCtx,
+    toks: &[GToken],
+    ty: Type,
+) -> PResult<(String, RewriteRule)> {
     let n = toks.len();
     let rule_ident = match &toks[0].kind {
         GTokenKind::Ident { name } => name.clone(),
-        _ => return Err(ParseFail::ExpectedIdentifier),
+        _ => return Err(ParseFail::ExpectedIdentifier.at(toks[0].span)),
     };
+    if n == 1 {
+        return Err(ParseFail::ExpectedBars.at(toks[0].span));
+    }
 
     let mut branches = vec![];
-    let mut terminal_branches = vec![];
-    let mut purely_terminal = true;
     let mut i = 1;
     let mut j = 1;
     loop {
         // skip initial bars
-        while toks[j].kind == GTokenKind::Bar {
+        while j < n && toks[j].kind == GTokenKind::Bar {
             j += 1;
         }
         // seek to end of branch
         while j < n && toks[j].kind != GTokenKind::Bar {
             j += 1;
         }
-        let branch = parse_branch(&toks[i..j])?;
-        if matches!(&branch.expr, Expression::ToBeReplaced { .. }) {
-            // NOTE: at this point these are just candidates, they need to be filtered
-            terminal_branches.push(branches.len());
-        }
-        if !matches!(&branch.expr, Expression::Terminal { .. }) {
-            purely_terminal = false;
-        }
+        let branch = parse_branch(ctx, &toks[i..j], ty)?;
         branches.push(branch);
         if j == n {
             break;
@@ -249,103 +1255,200 @@ fn parse_rewrite_rule(toks: &[GToken]) -> PResult<(String, RewriteRule)> {
         rule_ident,
         RewriteRule {
             branches,
-            terminal_branches,
-            purely_terminal,
+            ty,
+            // NOTE: only known once all rules are parsed
+            min_depth: usize::MAX,
         },
     );
     Ok(out)
 }
 
-fn parse_branch(toks: &[GToken]) -> PResult<Branch> {
+fn parse_branch(ctx: &mut ParseCtx, toks: &[GToken], ty: Type) -> PResult<Branch> {
     let mut i = 0;
     let mut weight = 0;
-    while toks[i].kind == GTokenKind::Bar {
+    while i < toks.len() && toks[i].kind == GTokenKind::Bar {
         weight += 1;
         i += 1;
     }
     if weight == 0 {
-        Err(ParseFail::ExpectedBars)
+        Err(ParseFail::ExpectedBars.at(toks[0].span))
+    } else if i == toks.len() {
+        Err(ParseFail::EmptyExpression.at(toks[i - 1].span))
     } else {
+        let (expr, got) = parse_expr(ctx, &toks[i..])?;
+        if got != ty {
+            return Err(ParseFail::TypeMismatch { expected: ty, got }.at(toks[i].span));
+        }
         Ok(Branch {
             weight,
-            expr: parse_expr(&toks[i..])?,
+            expr,
+            min_depth: usize::MAX,
         })
     }
 }
 
-macro_rules! check_function {
-    ($id: expr, $given_nargs: tt) => {
-        match FUNCTION_WHITELIST.get(&$id.as_str()) {
-            Some(&expected) => {
-                if expected != $given_nargs {
-                    return Err(ParseFail::WrongNumberOfFunctionArgs {
-                        func: $id,
-                        expected,
-                        got: $given_nargs,
-                    });
-                }
-            }
-            None => {
-                return Err(ParseFail::FunctionNotWhitelisted($id));
-            }
-        }
-    };
-}
-
-fn parse_expr(toks: &[GToken]) -> PResult<Expression> {
+/// parses an expression, along with the type it produces
+fn parse_expr(ctx: &mut ParseCtx, toks: &[GToken]) -> PResult<(Expression, Type)> {
     let n = toks.len();
     if n == 0 {
-        return Err(ParseFail::EmptyExpression);
+        return Err(ParseFail::EmptyExpression.into());
     }
     let ident = match &toks[0].kind {
         GTokenKind::Ident { name } => name.clone(),
-        _ => return Err(ParseFail::ExpectedIdentifier),
+        GTokenKind::Number { text } if n == 1 => {
+            return match text.parse::<f32>() {
+                Ok(x) if x.is_finite() => Ok((Expression::Terminal(Term::Literal(x)), Type::Float)),
+                _ => Err(ParseFail::InvalidNumber(text.clone()).at(toks[0].span)),
+            };
+        }
+        _ => return Err(ParseFail::ExpectedIdentifier.at(toks[0].span)),
     };
+    let ident_span = toks[0].span;
     if n == 1 {
         match Term::from_str(&ident) {
-            Some(term) => Ok(Expression::Terminal(term)),
-            None => Ok(Expression::ToBeReplaced { rule: ident }),
+            Some(term) => {
+                let ty = term.ty();
+                Ok((Expression::Terminal(term), ty))
+            }
+            None => match ctx.rule_types.get(&ident) {
+                Some(ty) => {
+                    ctx.refs.push((ident.clone(), ident_span));
+                    Ok((Expression::ToBeReplaced { rule: ident }, *ty))
+                }
+                None => match ctx.lets.iter().find(|def| def.name == ident) {
+                    Some(def) => {
+                        let ty = def.ty;
+                        ctx.var_refs.push(ident.clone());
+                        Ok((Expression::Terminal(Term::Var { name: ident, ty }), ty))
+                    }
+                    None => Err(ParseFail::UndefinedRule(ident).at(ident_span)),
+                },
+            },
         }
     } else if toks[1].kind == GTokenKind::LPar && toks[n - 1].kind == GTokenKind::RPar {
-        let argss = split_arglist(&toks[2..n - 1]);
+        // NOTE: split_arglist would give one empty argument for `()`
+        let argss = match n {
+            3 => vec![],
+            _ => split_arglist(&toks[2..n - 1]),
+        };
         let mut args = vec![];
+        let mut arg_types = vec![];
+        let mut arg_spans = vec![];
+        // index of the delimiter preceding the current argument
+        let mut delim = 1;
         for s in argss.into_iter() {
-            let arg = parse_expr(s)?;
-            args.push(Box::new(arg))
-        }
-        let expr = match args.len() {
-            1 => {
-                check_function!(ident, 1);
-                Expression::Func1 {
-                    ident,
-                    args: [args.pop().unwrap()],
-                }
+            if s.is_empty() {
+                // point at the delimiter following the missing argument
+                return Err(ParseFail::EmptyExpression.at(toks[delim + 1].span));
             }
-            2 => {
-                check_function!(ident, 2);
-                let arg2 = args.pop().unwrap();
-                let arg1 = args.pop().unwrap();
-                Expression::Func2 {
-                    ident,
-                    args: [arg1, arg2],
-                }
+            let (arg, ty) = parse_expr(ctx, s)?;
+            args.push(arg);
+            arg_types.push(ty);
+            arg_spans.push(s[0].span);
+            delim += s.len() + 1;
+        }
+        if ident == WARP {
+            return warp_expr(args, &arg_types, &arg_spans, ident_span);
+        }
+        let ty = check_call(ctx, &ident, &arg_types, &arg_spans, ident_span)?;
+        Ok((Expression::Func { ident, args }, ty))
+    } else if toks[1].kind == GTokenKind::LBracket && toks[n - 1].kind == GTokenKind::RBracket {
+        match Term::from_str(&ident) {
+            Some(Term::RandConst { .. }) => parse_random_spec(&toks[2..n - 1])
+                .map(|term| (Expression::Terminal(term), Type::Float))
+                .ok_or(ParseFail::BadRandomSpec.at(ident_span)),
+            _ => Err(ParseFail::BadArglist.at(toks[1].span)),
+        }
+    } else if toks[1].kind != GTokenKind::LPar {
+        Err(ParseFail::BadArglist.at(toks[1].span))
+    } else {
+        Err(ParseFail::BadArglist.at(toks[n - 1].span))
+    }
+}
+
+/// checks a call against the registered signature, giving its return type
+fn check_call(
+    ctx: &ParseCtx,
+    ident: &str,
+    arg_types: &[Type],
+    arg_spans: &[TokenSpan],
+    ident_span: TokenSpan,
+) -> PResult<Type> {
+    let def = match ctx.funcs.get(ident) {
+        Some(def) if def.arity() != arg_types.len() => {
+            return Err(ParseFail::WrongNumberOfFunctionArgs {
+                func: ident.to_string(),
+                expected: def.arity(),
+                got: arg_types.len(),
             }
-            3 => {
-                check_function!(ident, 3);
-                let arg3 = args.pop().unwrap();
-                let arg2 = args.pop().unwrap();
-                let arg1 = args.pop().unwrap();
-                Expression::Func3 {
-                    ident,
-                    args: [arg1, arg2, arg3],
-                }
+            .at(ident_span))
+        }
+        Some(def) => def,
+        None => return Err(ParseFail::FunctionNotRegistered(ident.to_string()).at(ident_span)),
+    };
+    def.resolve(arg_types).map_err(|(i, expected)| {
+        ParseFail::TypeMismatch {
+            expected,
+            got: arg_types[i],
+        }
+        .at(arg_spans[i])
+    })
+}
+
+/// checks the coordinates given to a warp, the body may be of any type
+fn warp_expr(
+    args: Vec<Expression>,
+    arg_types: &[Type],
+    arg_spans: &[TokenSpan],
+    ident_span: TokenSpan,
+) -> PResult<(Expression, Type)> {
+    let coords: &[Type] = match args.len() {
+        2 => &[Type::Vec2],
+        3 => &[Type::Float, Type::Float],
+        got => {
+            return Err(ParseFail::WrongNumberOfFunctionArgs {
+                func: WARP.to_string(),
+                expected: 3,
+                got,
             }
-            n => return Err(ParseFail::UnsupportedNumberOfFunctionArgs(n)),
-        };
-        Ok(expr)
-    } else {
-        Err(ParseFail::BadArglist)
+            .at(ident_span))
+        }
+    };
+    for (i, expected) in coords.iter().enumerate() {
+        if arg_types[i] != *expected {
+            return Err(ParseFail::TypeMismatch {
+                expected: *expected,
+                got: arg_types[i],
+            }
+            .at(arg_spans[i]));
+        }
     }
+    let ty = arg_types[args.len() - 1];
+    Ok((Expression::Warp { args, ty }, ty))
+}
+
+fn parse_random_spec(toks: &[GToken]) -> Option<Term> {
+    let mut nums = vec![];
+    for s in split_arglist(toks) {
+        match s {
+            [GToken {
+                kind: GTokenKind::Number { text },
+                ..
+            }] => nums.push(text.as_str()),
+            _ => return None,
+        }
+    }
+    let (min, max, decimals) = match nums[..] {
+        [min, max] => (min, max, "2"),
+        [min, max, decimals] => (min, max, decimals),
+        _ => return None,
+    };
+    let min: f32 = min.parse().ok().filter(|x: &f32| x.is_finite())?;
+    let max: f32 = max.parse().ok().filter(|x: &f32| x.is_finite())?;
+    let decimals: u8 = decimals.parse().ok().filter(|d| *d <= MAX_DECIMALS)?;
+    // NOTE: also rules out min > max
+    rounded_range(min, max, decimals)?;
+    Some(Term::RandConst { min, max, decimals })
 }
 
 fn split_arglist(toks: &[GToken]) -> Vec<&[GToken]> {
@@ -354,10 +1457,10 @@ fn split_arglist(toks: &[GToken]) -> Vec<&[GToken]> {
     let mut args = vec![];
     let mut level: i32 = 0;
     while k < toks.len() {
-        if toks[k].kind == GTokenKind::LPar {
+        if matches!(toks[k].kind, GTokenKind::LPar | GTokenKind::LBracket) {
             level += 1;
         }
-        if toks[k].kind == GTokenKind::RPar {
+        if matches!(toks[k].kind, GTokenKind::RPar | GTokenKind::RBracket) {
             level -= 1;
         }
         if level == 0 && toks[k].kind == GTokenKind::Comma {
@@ -369,3 +1472,354 @@ fn split_arglist(toks: &[GToken]) -> Vec<&[GToken]> {
     args.push(&toks[j..]);
     args
 }
+
+#[test]
+fn parse_error_span_test() {
+    let src = "C | T\n  | sin(T, T)\n  ;\nT | u ;";
+    let err = parse_rewrite_rules(src).unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::WrongNumberOfFunctionArgs {
+            expected: 1,
+            got: 2,
+            ..
+        }
+    ));
+    let span = err.span.unwrap();
+    assert_eq!((span.line, span.startchar, span.length), (1, 4, 3));
+    assert!(err.report(src).ends_with("  | sin(T, T)\n    ^^^"));
+
+    let err = parse_rewrite_rules("C | add(u,) ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::EmptyExpression));
+    assert_eq!(err.span.unwrap().startchar, 10);
+}
+
+#[test]
+fn literal_test() {
+    let rr = parse_rewrite_rules("C | mult(T, 0.5) | T ; T | -2 | .25 | 1e-3 | u ;").unwrap();
+    assert_eq!(rr.rules["C"].branches[0].expr.as_string(), "mult(_,0.5)");
+    let t: Vec<_> = rr.rules["T"]
+        .branches
+        .iter()
+        .map(|b| b.expr.as_string())
+        .collect();
+    assert_eq!(t, ["-2.0", "0.25", "0.001", "u"]);
+
+    let err = parse_rewrite_rules("C | add(u, 1e) ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::TokeniserErr(GTokenError::MalformedNumber)
+    ));
+    // a lone minus is only valid in function declarations
+    let err = parse_rewrite_rules("C | add(u, -) ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::ExpectedIdentifier));
+    let err = parse_rewrite_rules("C | T ; T | 1e99 ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::InvalidNumber(_)));
+}
+
+#[test]
+fn random_spec_test() {
+    let src = "C | T | sig(T, T, random[0, 1]) ; T | random[0, 5] | random[-5, 5, 0] ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    assert!(matches!(
+        rr.rules["T"].branches[1].expr,
+        Expression::Terminal(Term::RandConst {
+            min: -5.0,
+            max: 5.0,
+            decimals: 0
+        })
+    ));
+    for _ in 0..20 {
+        let mut expr = rr.rules["T"].branches[0].expr.clone();
+        expr.resolve_rand_consts();
+        let x = expr
+            .eval(&rr.funcs, &Default::default(), 0.0, 0.0, 0.0, 0.0)
+            .comps()[0];
+        assert!((0.0..=5.0).contains(&x));
+        assert!(((x * 100.0).round() - x * 100.0).abs() < 1e-3);
+    }
+    // 0.1 and 0.3 would round out of range
+    let rr = parse_rewrite_rules("C | random[0.15, 0.25, 1] ;").unwrap();
+    for _ in 0..20 {
+        let mut expr = rr.rules["C"].branches[0].expr.clone();
+        expr.resolve_rand_consts();
+        assert_eq!(expr.as_string(), "0.2");
+    }
+
+    for bad in [
+        "random[1, 0]",
+        "random[0, 1, 9]",
+        "random[0]",
+        "random[u, 1]",
+        "random[0, 1, 0.5]",
+        "random[0.001, 0.004]",
+    ] {
+        let err = parse_rewrite_rules(&format!("C | T ; T | {bad} ;")).unwrap_err();
+        assert!(matches!(err.kind, ParseFail::BadRandomSpec), "{bad}");
+    }
+}
+
+#[test]
+fn rule_reference_test() {
+    let src = "C | T | sin(X) ;\nT | u ;";
+    let err = parse_rewrite_rules(src).unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UndefinedRule(ref rule) if rule == "X"));
+    assert_eq!(err.span.unwrap().startchar, 12);
+
+    let src = "C | T ;\nT | u ;\nU | mult(T, V) ;\nV | v ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let unreachable: Vec<_> = rr
+        .warnings
+        .iter()
+        .filter_map(|w| match &w.kind {
+            ParseWarn::UnreachableRule(rule) => Some((rule.as_str(), w.span.unwrap().line)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(unreachable, [("U", 2), ("V", 3)]);
+}
+
+#[test]
+fn min_depth_test() {
+    // terminating requires several hops from the entry point
+    let src = "C | A | sin(C) ; A | B ; B | mult(D, D) ; D | u ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let min_depths: Vec<_> = ["C", "A", "B", "D"]
+        .iter()
+        .map(|rule| rr.rules[*rule].min_depth)
+        .collect();
+    assert_eq!(min_depths, [4, 3, 2, 1]);
+
+    fn depth(expr: &Expression) -> usize {
+        1 + expr.args().iter().map(depth).max().unwrap_or(0)
+    }
+    let rr = parse_rewrite_rules(crate::shader::DEFAULT_GRAMMAR).unwrap();
+    for seed in 0..20 {
+        crate::funcgen::seed_rng(seed);
+        for max_depth in 1..12 {
+            assert!(depth(&rr.gen_fn("C", max_depth)) <= max_depth.max(rr.rules["C"].min_depth));
+        }
+    }
+
+    let rr = parse_rewrite_rules("C | u | X ; X | sin(X) ;").unwrap();
+    assert!(matches!(
+        rr.warnings[..],
+        [Diagnostic {
+            kind: ParseWarn::UnproductiveRule(_),
+            ..
+        }]
+    ));
+    let err = parse_rewrite_rules("C | sin(C) ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UnproductiveEntryPoint(_)));
+}
+
+#[test]
+fn function_args_test() {
+    let rr = parse_rewrite_rules("C | mix(u, v, clamp(t, 0, 1)) ;").unwrap();
+    let expr = &rr.rules["C"].branches[0].expr;
+    assert_eq!(expr.args().len(), 3);
+    assert_eq!(expr.args()[2].args().len(), 3);
+
+    let err = parse_rewrite_rules("C | sin() ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::WrongNumberOfFunctionArgs { got: 0, .. }
+    ));
+}
+
+#[test]
+fn func_directive_test() {
+    let src = "@func wave(x, f) = sin(x*f) - 2;\nC | wave(u, 3) | T ;\nT | t ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let def = rr.funcs.get("wave").unwrap();
+    assert_eq!(def.arity(), 2);
+    assert_eq!(
+        def.glsl.as_deref(),
+        Some("float wave(float x, float f) {\n    return sin(x * f) - 2.0;\n}")
+    );
+
+    let err = parse_rewrite_rules("@func wave(x) = sin(x*f);\nC | u ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UnknownVariable(ref v) if v == "f"));
+    let err = parse_rewrite_rules("@func sin(x) = x;\nC | u ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::BuiltinRedeclared(_)));
+    // the shader's definition would be used instead
+    for src in [
+        "@func add(x, y) = x - y;",
+        "@func vec2 add(vec2 p, vec2 q) = p + q;",
+    ] {
+        let err = parse_rewrite_rules(&format!("{src}\nC | u ;")).unwrap_err();
+        assert!(matches!(err.kind, ParseFail::ShaderFuncRedeclared(ref f) if f == "add"));
+    }
+    // names the shader uses for other things
+    for name in ["t", "u", "color", "main", "red", "_tmp0", "a0"] {
+        let src = format!("@let a0 = C;\n@func {name}(x) = x;\nC | u ;");
+        let err = parse_rewrite_rules(&src).unwrap_err();
+        assert!(matches!(err.kind, ParseFail::NameTaken(_)), "{name}");
+    }
+    for name in ["step", "distance", "texture"] {
+        let err = parse_rewrite_rules(&format!("@func {name}(x) = x;\nC | u ;")).unwrap_err();
+        assert!(
+            matches!(err.kind, ParseFail::BuiltinRedeclared(_)),
+            "{name}"
+        );
+    }
+    let funcs = FunctionRegistry::from_shader("float halve(float x) { return 0.5 * x; }");
+    let err = parse_rewrite_rules_with("@func halve(x) = x;\nC | u ;", funcs).unwrap_err();
+    assert!(matches!(err.kind, ParseFail::ShaderFuncRedeclared(_)));
+    // calls are checked as in rules
+    let err = parse_rewrite_rules("@func f(x) = sin(x, x, x);\nC | u ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::WrongNumberOfFunctionArgs { .. }
+    ));
+    let err = parse_rewrite_rules("@func f(vec2 p) = length(p) * p;\nC | u ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::TypeMismatch { .. }));
+    let rr = parse_rewrite_rules("@func vec2 f(vec2 p) = -(p - 1) * 2;\nC | u ;").unwrap();
+    assert!(rr
+        .funcs
+        .get("f")
+        .unwrap()
+        .glsl
+        .as_ref()
+        .unwrap()
+        .contains("return -(p - 1.0) * 2.0;"));
+    // a minus right before a number still subtracts
+    let src = "@func f(x) = x-1;\n@func g(x) = 2*x-.5;\n@func h(x) = f(x)-1e-3;\nC | u ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let body = |name| rr.funcs.get(name).unwrap().glsl.clone().unwrap();
+    assert!(body("f").contains("return x - 1.0;"));
+    assert!(body("g").contains("return 2.0 * x - 0.5;"));
+    assert!(body("h").contains("return f(x) - 0.001;"));
+}
+
+#[test]
+fn entry_directive_test() {
+    let src = "@entry blue = B;\nC | sin(C) | u ;\nB | v ;\nU | t ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    let entries: Vec<_> = CHANNELS.iter().map(|ch| rr.entry(ch)).collect();
+    assert_eq!(entries, ["C", "C", "B"]);
+    assert!(matches!(
+        rr.warnings[..],
+        [Diagnostic { kind: ParseWarn::UnreachableRule(ref rule), .. }] if rule == "U"
+    ));
+
+    // typed rules coming first are skipped
+    let rr = parse_rewrite_rules("vec2 P | uv | rotate(P, C) ;\nC | length(P) | u ;").unwrap();
+    assert_eq!(rr.entry("red"), "C");
+
+    let err = parse_rewrite_rules("@entry alpha = B;\nC | u ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UnknownChannel(_)));
+    let err = parse_rewrite_rules("@entry red = B;\nC | u ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UndefinedRule(ref rule) if rule == "B"));
+    assert_eq!(err.span.unwrap().startchar, 13);
+}
+
+#[test]
+fn typed_rules_test() {
+    let src = "C | length(P) | u ;\nvec2 P | uv | rotate(P, C) ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    assert_eq!(rr.rules["P"].ty, Type::Vec2);
+    assert_eq!(rr.rules["C"].ty, Type::Float);
+
+    let err = parse_rewrite_rules("C | P ;\nvec2 P | uv ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::TypeMismatch {
+            expected: Type::Float,
+            got: Type::Vec2
+        }
+    ));
+    let err = parse_rewrite_rules("C | sin(u) ;\nvec2 P | rotate(u, C) ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::TypeMismatch {
+            expected: Type::Vec2,
+            ..
+        }
+    ));
+    // channels are floats, a vec3 has to be the color entry
+    let err = parse_rewrite_rules("vec3 Col | vec3(u, v, t) ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::TypeMismatch { .. }));
+}
+
+#[test]
+fn warp_test() {
+    let src = "C | warp(v, u, C) | warp(rotate(uv, t), C) | sin(C) | r ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    assert!(matches!(
+        rr.rules["C"].branches[0].expr,
+        Expression::Warp {
+            ty: Type::Float,
+            ..
+        }
+    ));
+    assert_eq!(rr.rules["C"].branches[1].expr.args().len(), 2);
+
+    let err = parse_rewrite_rules("C | warp(uv, u, C) | u ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::TypeMismatch {
+            expected: Type::Float,
+            ..
+        }
+    ));
+    let err = parse_rewrite_rules("C | warp(C) | u ;").unwrap_err();
+    assert!(matches!(
+        err.kind,
+        ParseFail::WrongNumberOfFunctionArgs { got: 1, .. }
+    ));
+    let err = parse_rewrite_rules("@func warp(x) = x;\nC | u ;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::BuiltinRedeclared(_)));
+}
+
+#[test]
+fn let_directive_test() {
+    let src = "@let a0 = A;\nC | add(a0, u) | a0 ;\nA | sin(v) | t ;";
+    let rr = parse_rewrite_rules(src).unwrap();
+    assert_eq!(rr.lets, [("a0".to_string(), "A".to_string())]);
+    assert!(rr.warnings.is_empty());
+    let Expression::Terminal(Term::Var { name, ty }) = &rr.rules["C"].branches[1].expr else {
+        panic!()
+    };
+    assert_eq!((name.as_str(), *ty), ("a0", Type::Float));
+
+    // A uses a1 through B, but a0 is generated before a1
+    let src = "@let a0 = A;\n@let a1 = C;\nC | u ;\nA | B ;\nB | sin(a1) ;";
+    let err = parse_rewrite_rules(src).unwrap_err();
+    assert!(matches!(err.kind, ParseFail::LetUsesLaterLet { ref binding, .. } if binding == "a0"));
+    let err = parse_rewrite_rules("C | a0 ;\n@let a0 = C;").unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UndefinedRule(ref v) if v == "a0"));
+    for name in ["C", "sin", "red", "u"] {
+        let src = format!("@let {name} = C;\nC | u ;");
+        let err = parse_rewrite_rules(&src).unwrap_err();
+        assert!(matches!(err.kind, ParseFail::NameTaken(_)), "{name}");
+    }
+    for name in ["float", "if", "half", "input", "mat2x3", "gl_Pos", "a__b"] {
+        for src in [
+            format!("@let {name} = C;"),
+            format!("@func {name}(x) = x;"),
+            format!("@func f({name}) = 1;"),
+        ] {
+            let err = parse_rewrite_rules(&format!("{src}\nC | u ;")).unwrap_err();
+            assert!(matches!(err.kind, ParseFail::ReservedName(_)), "{src}");
+        }
+    }
+}
+
+#[test]
+fn parse_expression_test() {
+    let funcs = FunctionRegistry::builtin();
+    let vars = [("a0".to_string(), Type::Vec2)];
+    let src = "add(sig(-0.5,r,1.0),length(warp(a0,rotate(uv,t))))";
+    let (expr, ty) = parse_expression(src, &funcs, &vars).unwrap();
+    assert_eq!(ty, Type::Float);
+    assert_eq!(expr.as_string(), src);
+
+    let err = parse_expression("add(u, C)", &funcs, &vars).unwrap_err();
+    assert!(matches!(err.kind, ParseFail::UndefinedRule(ref name) if name == "C"));
+    assert_eq!(err.span.unwrap().startchar, 7);
+    let err = parse_expression("rotate(u,t)", &funcs, &vars).unwrap_err();
+    assert!(matches!(err.kind, ParseFail::TypeMismatch { .. }));
+    let err = parse_expression("u;", &funcs, &vars).unwrap_err();
+    assert!(matches!(err.kind, ParseFail::BadArglist));
+}
@@ -0,0 +1,147 @@
+//! Whole sessions saved as project files, to come back to later or share.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codegen::CodegenOpts,
+    functions::FunctionRegistry,
+    headless::{Binding, GeneratedFuncs, Slot},
+    json::{from_json, to_json, JsonError},
+    parser::{parse_rewrite_rules_with, ParseError, RewriteRules},
+};
+
+/// bumped whenever older project files can no longer be loaded
+pub const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Json(JsonError),
+    /// saved by a newer (or much older) version
+    UnsupportedVersion(u32),
+    Grammar(ParseError),
+    /// a generated function (named after its binding or slot) that does not parse back, e.g.
+    /// after editing the file by hand
+    Generated(String, ParseError),
+}
+
+impl From<JsonError> for ProjectError {
+    fn from(value: JsonError) -> Self {
+        Self::Json(value)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Project {
+    pub version: u32,
+    pub grammar: String,
+    /// fragment shader template
+    pub frag: String,
+    pub next_seed: u64,
+    pub last_seed: u64,
+    pub max_depth: usize,
+    pub t_max: f64,
+    pub simplify: bool,
+    pub codegen_opts: CodegenOpts,
+    pub slots: Vec<Slot>,
+    /// the functions last generated from `last_seed` (or edited since), kept as they are
+    pub generated: GeneratedFuncs,
+}
+
+impl Project {
+    pub fn to_json(&self) -> Result<String, JsonError> {
+        to_json(self)
+    }
+    pub fn from_json(src: &str) -> Result<Self, ProjectError> {
+        let project: Self = from_json(src)?;
+        if project.version != VERSION {
+            return Err(ProjectError::UnsupportedVersion(project.version));
+        }
+        Ok(project)
+    }
+    /// Parses the grammar (with the functions the shader defines) and has the generated
+    /// functions parsed back against it (see `GeneratedFuncs::from_strings`), as project files
+    /// may have been edited by hand and could not be rendered or evaluated safely otherwise.
+    pub fn check(&mut self) -> Result<RewriteRules, ProjectError> {
+        let funcs = FunctionRegistry::from_shader(&self.frag);
+        let rr = parse_rewrite_rules_with(&self.grammar, funcs).map_err(ProjectError::Grammar)?;
+        let strings = |bindings: &[Binding]| -> Vec<_> {
+            bindings
+                .iter()
+                .map(|b| (b.name.clone(), b.expr.as_string()))
+                .collect()
+        };
+        let (lets, slots) = (
+            strings(&self.generated.lets),
+            strings(&self.generated.slots),
+        );
+        self.generated = GeneratedFuncs::from_strings(&lets, &slots, &rr.funcs)
+            .map_err(|(name, e)| ProjectError::Generated(name, e))?;
+        Ok(rr)
+    }
+}
+
+#[test]
+fn project_test() {
+    use crate::{
+        headless::gen_channels,
+        parser::parse_rewrite_rules,
+        shader::{DEFAULT_FRAG, DEFAULT_GRAMMAR},
+    };
+
+    let rr = parse_rewrite_rules(DEFAULT_GRAMMAR).unwrap();
+    let project = Project {
+        version: VERSION,
+        grammar: DEFAULT_GRAMMAR.to_string(),
+        frag: DEFAULT_FRAG.to_string(),
+        next_seed: 8,
+        last_seed: 7,
+        max_depth: 10,
+        t_max: 5.0,
+        simplify: true,
+        codegen_opts: Default::default(),
+        slots: Slot::defaults(&rr, 10),
+        generated: gen_channels(&rr, 7, 10),
+    };
+    let json = project.to_json().unwrap();
+    let loaded = Project::from_json(&json).unwrap();
+    assert_eq!(loaded.grammar, project.grammar);
+    assert_eq!(loaded.slots.len(), 3);
+    let opts = Default::default();
+    assert_eq!(
+        loaded
+            .generated
+            .render(&loaded.frag, &rr.funcs, opts)
+            .unwrap(),
+        project
+            .generated
+            .render(&project.frag, &rr.funcs, opts)
+            .unwrap()
+    );
+
+    let mut checked = Project::from_json(&json).unwrap();
+    checked.check().unwrap();
+    assert_eq!(
+        to_json(&checked.generated).unwrap(),
+        to_json(&project.generated).unwrap()
+    );
+    // functions edited into something that cannot be evaluated
+    for expr in [
+        "{\"Warp\":{\"args\":[],\"ty\":\"Float\"}}",
+        "{\"Func\":{\"ident\":\"rotate\",\"args\":[{\"Terminal\":\"U\"}]}}",
+    ] {
+        let mut broken = Project::from_json(&json).unwrap();
+        broken.generated.slots[0].expr = from_json(expr).unwrap();
+        assert!(
+            matches!(broken.check(), Err(ProjectError::Generated(ref name, _)) if name == "red")
+        );
+    }
+    let mut broken = Project::from_json(&json).unwrap();
+    broken.grammar = "C | sin(C, C) ;".to_string();
+    assert!(matches!(broken.check(), Err(ProjectError::Grammar(_))));
+
+    let json = json.replacen(&format!("\"version\": {VERSION}"), "\"version\": 0", 1);
+    assert!(matches!(
+        Project::from_json(&json),
+        Err(ProjectError::UnsupportedVersion(0))
+    ));
+}
@@ -0,0 +1,363 @@
+//! Putting generated functions into fragment shader source.
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::functions::{scan_funcs, FunctionRegistry};
+
+pub static DEFAULT_GRAMMAR: &str = include_str!("../grammar.bnf");
+pub static DEFAULT_FRAG: &str = include_str!("../default_frag.glsl");
+
+/// placeholder for the definitions generated code needs (grammar functions and helpers)
+pub const DEFS: &str = "@defs";
+/// placeholder for the statements generated values need (bindings and temporaries)
+pub const STMTS: &str = "@stmts";
+
+#[derive(Debug)]
+pub enum TemplateError {
+    /// nothing was generated for the placeholder
+    UnknownPlaceholder(String),
+    /// without a `{{@defs}}` placeholder definitions go right before `void main(`, which could
+    /// not be found
+    MainNotFound,
+    /// without a `{{@stmts}}` placeholder statements go before the (top level) statement of
+    /// `main` with the first generated value inside it, but there is none
+    NoValuesInMain,
+    /// the value uses bindings or temporaries, which are declared in `main`, but is outside it
+    ValueOutsideMain(String),
+}
+
+/// A fragment shader with `{{name}}` placeholders for generated values (e.g.
+/// `float red = {{red}};`), which can go anywhere and be used any number of times.
+pub struct Template<'a> {
+    src: &'a str,
+    /// byte ranges of the placeholders (braces included) along with their names
+    placeholders: Vec<(Range<usize>, &'a str)>,
+}
+
+fn is_placeholder_name(name: &str) -> bool {
+    let name = name.strip_prefix('@').unwrap_or(name);
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// start of the line `out` currently ends on
+fn line_start(out: &str) -> usize {
+    out.rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+/// the positions and bytes of `src` from `from` on, leaving out comments
+fn outside_comments(src: &str, from: usize) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let bytes = src.as_bytes();
+    let mut i = from;
+    std::iter::from_fn(move || loop {
+        if i >= bytes.len() {
+            return None;
+        } else if bytes[i..].starts_with(b"//") {
+            i += src[i..].find('\n').unwrap_or(src.len() - i);
+        } else if bytes[i..].starts_with(b"/*") {
+            i += src[i..].find("*/").map_or(src.len() - i, |end| end + 2);
+        } else {
+            i += 1;
+            return Some((i - 1, bytes[i - 1]));
+        }
+    })
+}
+
+/// whether the statement goes on past the block ending at `pos`, as in `} else {` (or `do {}
+/// while`, though taking a while loop after a block as part of it does no harm either)
+fn continues_after_block(src: &str, pos: usize) -> bool {
+    let word: Vec<_> = outside_comments(src, pos)
+        .map(|(_, c)| c)
+        .skip_while(u8::is_ascii_whitespace)
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        .collect();
+    word == b"else" || word == b"while"
+}
+
+/// Start of the top level statement of `main` that `pos` is in, given the body of `main`: past
+/// the last `;` or block ending that `main` itself has before it, and any whitespace and
+/// comments after that.
+fn statement_start(src: &str, main: &Range<usize>, pos: usize) -> usize {
+    let mut start = main.start + 1;
+    // within the body of main
+    let (mut braces, mut parens) = (0, 0);
+    for (i, c) in outside_comments(src, start).take_while(|(i, _)| *i < pos) {
+        match c {
+            b'(' => parens += 1,
+            b')' => parens -= 1,
+            b'{' => braces += 1,
+            b'}' => {
+                braces -= 1;
+                if braces == 0 && !continues_after_block(src, i + 1) {
+                    start = i + 1;
+                }
+            }
+            b';' if braces == 0 && parens == 0 => start = i + 1,
+            _ => {}
+        }
+    }
+    loop {
+        let rest = &src[start..pos];
+        let trimmed = rest.trim_start();
+        start += rest.len() - trimmed.len();
+        let comment = match trimmed {
+            _ if trimmed.starts_with("//") => trimmed.find('\n'),
+            _ if trimmed.starts_with("/*") => trimmed.find("*/").map(|end| end + 2),
+            _ => None,
+        };
+        match comment {
+            Some(len) => start += len,
+            None => return start,
+        }
+    }
+}
+
+/// the name a statement such as `float a0 = sin(u);` declares
+fn declared_name(stmt: &str) -> Option<&str> {
+    stmt.split_whitespace().nth(1)
+}
+
+fn uses_any(value: &str, names: &[&str]) -> bool {
+    value
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|tok| names.contains(&tok))
+}
+
+/// start of the line `void main(` is on
+fn main_start(src: &str) -> Option<usize> {
+    let mut pos = 0;
+    for line in src.split_inclusive('\n') {
+        if line.trim_start().starts_with("void main(") {
+            return Some(pos);
+        }
+        pos += line.len();
+    }
+    None
+}
+
+/// byte range of the body of `main`, braces included
+fn main_body(src: &str) -> Option<Range<usize>> {
+    let start = main_start(src)?;
+    let open = start + src[start..].find('{')?;
+    let mut level = 0;
+    for (i, c) in outside_comments(src, open) {
+        match c {
+            b'{' => level += 1,
+            b'}' => level -= 1,
+            _ => continue,
+        }
+        if level == 0 {
+            return Some(open..i + 1);
+        }
+    }
+    None
+}
+
+impl<'a> Template<'a> {
+    pub fn new(src: &'a str) -> Self {
+        let mut placeholders = vec![];
+        let mut from = 0;
+        while let Some(start) = src[from..].find("{{").map(|i| i + from) {
+            // NOTE: anything else between double braces is left as it is, as GLSL blocks can be
+            // nested
+            match src[start + 2..].find("}}") {
+                Some(len) if is_placeholder_name(src[start + 2..start + 2 + len].trim()) => {
+                    let end = start + len + 4;
+                    placeholders.push((start..end, src[start + 2..end - 2].trim()));
+                    from = end;
+                }
+                _ => from = start + 1,
+            }
+        }
+        Self { src, placeholders }
+    }
+
+    /// names of the placeholders, in order of appearance
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.placeholders.iter().map(|(_, name)| *name)
+    }
+
+    pub fn uses(&self, name: &str) -> bool {
+        self.names().any(|n| n == name)
+    }
+
+    /// Fills the placeholders with `values`, `{{@defs}}` with `defs` and `{{@stmts}}` with
+    /// `stmts`, each on a line of its own at the indentation of the placeholder. Values using
+    /// what `stmts` declare have to be inside `main`.
+    pub fn render(
+        &self,
+        values: &HashMap<String, String>,
+        defs: &[String],
+        stmts: &[String],
+    ) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut last = 0;
+        let mut stmts_pending = !self.uses(STMTS) && !stmts.is_empty();
+        let main = main_body(self.src);
+        let declared: Vec<_> = stmts.iter().filter_map(|s| declared_name(s)).collect();
+        for (range, name) in self.placeholders.iter() {
+            out.push_str(&self.src[last..range.start]);
+            let copied_from = last;
+            last = range.end;
+            let (block, sep) = match *name {
+                DEFS => (defs, "\n\n"),
+                STMTS => (stmts, "\n"),
+                _ => {
+                    let value = values
+                        .get(*name)
+                        .ok_or_else(|| TemplateError::UnknownPlaceholder(name.to_string()))?;
+                    let main = main.as_ref().filter(|main| main.contains(&range.start));
+                    if main.is_none() && uses_any(value, &declared) {
+                        return Err(TemplateError::ValueOutsideMain(name.to_string()));
+                    }
+                    if let Some(main) = main.filter(|_| stmts_pending) {
+                        // NOTE: a placeholder earlier in the statement could only be a block one
+                        let start = statement_start(self.src, main, range.start).max(copied_from);
+                        let start = out.len() - (range.start - start);
+                        let line = &out[line_start(&out[..start])..];
+                        let indent = &line[..line.len() - line.trim_start().len()];
+                        let block: String =
+                            stmts.iter().map(|s| format!("{s}\n{indent}")).collect();
+                        out.insert_str(start, &block);
+                        stmts_pending = false;
+                    }
+                    out.push_str(value);
+                    continue;
+                }
+            };
+            let indent = &out[line_start(&out)..];
+            if !indent.trim().is_empty() {
+                out.push_str(&block.join(sep));
+            } else if block.is_empty() {
+                // drop the line the placeholder was on
+                out.truncate(line_start(&out));
+                last += self.src[last..].starts_with('\n') as usize;
+            } else {
+                out.push_str(&block.join(&format!("{sep}{indent}")));
+            }
+        }
+        out.push_str(&self.src[last..]);
+        if stmts_pending {
+            return Err(TemplateError::NoValuesInMain);
+        }
+        if !self.uses(DEFS) && !defs.is_empty() {
+            let main = main_start(&out).ok_or(TemplateError::MainNotFound)?;
+            out.insert_str(main, &format!("{}\n\n", defs.join("\n\n")));
+        }
+        Ok(out)
+    }
+}
+
+/// the GLSL definitions of registered functions that the shader does not define itself, in order
+/// of registration (so that e.g. `@func`s come after the ones they call)
+pub fn missing_func_defs(code: &str, funcs: &FunctionRegistry) -> Vec<String> {
+    let defined: Vec<_> = scan_funcs(code).into_iter().map(|f| f.0).collect();
+    funcs
+        .defined()
+        .filter(|(name, _)| !defined.contains(name))
+        .filter_map(|(_, def)| def.glsl.clone())
+        .collect()
+}
+
+#[test]
+fn template_test() {
+    let src = "void f() {{ }}\n{{@defs}}\nvoid main() {\n    {{@stmts}}\n    float x = {{ a }} + {{a}};\n}";
+    let template = Template::new(src);
+    assert_eq!(
+        template.names().collect::<Vec<_>>(),
+        [DEFS, STMTS, "a", "a"]
+    );
+    let values = HashMap::from([("a".to_string(), "sin(t)".to_string())]);
+    let stmts = ["float b = 1.0;".to_string(), "float c = b;".to_string()];
+    let code = template.render(&values, &[], &stmts).unwrap();
+    assert_eq!(
+        code,
+        "void f() {{ }}\nvoid main() {\n    float b = 1.0;\n    float c = b;\n    float x = sin(t) + sin(t);\n}"
+    );
+    let err = Template::new("{{b}}")
+        .render(&values, &[], &[])
+        .unwrap_err();
+    assert!(matches!(err, TemplateError::UnknownPlaceholder(ref name) if name == "b"));
+
+    // without the block placeholders
+    let template = Template::new("void main() {\n    float x = {{a}};\n}");
+    let defs = ["float g(float x) {\n    return x;\n}".to_string()];
+    let code = template.render(&values, &defs, &stmts[..1]).unwrap();
+    assert_eq!(
+        code,
+        "float g(float x) {\n    return x;\n}\n\nvoid main() {\n    float b = 1.0;\n    float x = sin(t);\n}"
+    );
+    // nor in the middle of a statement
+    let template = Template::new("void main() {\n    color = vec4(\n        {{a}}, 1.0); // {\n}");
+    let code = template.render(&values, &[], &stmts[..1]).unwrap();
+    assert_eq!(
+        code,
+        "void main() {\n    float b = 1.0;\n    color = vec4(\n        sin(t), 1.0); // {\n}"
+    );
+
+    // nor in a nested block, out of scope of the rest of main
+    let src = "void main() {\n    for (int i = 0; i < 2; i++) {}\n    if (t > 0.0) {\n        x = {{a}};\n    } else {\n        x = {{a}};\n    }\n}";
+    let code = Template::new(src)
+        .render(&values, &[], &stmts[..1])
+        .unwrap();
+    assert!(code.contains("i++) {}\n    float b = 1.0;\n    if (t > 0.0) {\n"));
+    let src = "void main() {\n    if (t > 0.0) {\n    } else if (t < 1.0) {\n        x = {{a}};\n    }\n}";
+    let code = Template::new(src)
+        .render(&values, &[], &stmts[..1])
+        .unwrap();
+    assert!(code.starts_with("void main() {\n    float b = 1.0;\n    if (t > 0.0) {\n"));
+
+    // values using the statements cannot go outside main
+    let template = Template::new("float f() {\n    return {{a}};\n}\nvoid main() {}");
+    let err = template.render(&values, &[], &stmts).unwrap_err();
+    assert!(matches!(err, TemplateError::NoValuesInMain));
+    assert!(template.render(&values, &[], &[]).is_ok());
+    let values = HashMap::from([
+        ("a".to_string(), "sin(t)".to_string()),
+        ("c".to_string(), "c*2.0".to_string()),
+    ]);
+    let src = "{{@defs}}\nvoid main() {\n    {{@stmts}}\n    float x = {{a}} + {{c}};\n}";
+    let template = Template::new(src);
+    assert!(template.render(&values, &[], &stmts).is_ok());
+    for src in [
+        "float f() {\n    return {{c}};\n}\nvoid main() {\n    float x = {{a}};\n}",
+        "void main() {\n    float x = {{a}};\n}\nfloat f() {\n    return {{c}};\n}",
+        "void main() {\n    {{@stmts}}\n    float x = {{a}};\n}\nfloat f() {\n    return {{c}};\n}",
+    ] {
+        let err = Template::new(src).render(&values, &[], &stmts).unwrap_err();
+        assert!(matches!(err, TemplateError::ValueOutsideMain(ref name) if name == "c"));
+    }
+    // values not using them can
+    let src = "float f() {\n    return {{a}};\n}\nvoid main() {\n    float x = {{c}};\n}";
+    let code = Template::new(src).render(&values, &[], &stmts).unwrap();
+    assert!(code.ends_with("    float b = 1.0;\n    float c = b;\n    float x = c*2.0;\n}"));
+}
+
+#[test]
+fn missing_func_defs_test() {
+    use crate::{
+        functions::{CpuImpl, FuncDef},
+        types::Type,
+    };
+
+    let mut funcs = FunctionRegistry::builtin();
+    funcs.register(
+        "halve",
+        FuncDef {
+            glsl: Some("float halve(float x) {\n    return 0.5 * x;\n}".to_string()),
+            cpu: Some(CpuImpl::Scalar(|a| 0.5 * a[0])),
+            ..FuncDef::new(vec![Type::Float], Type::Float)
+        },
+    );
+    assert_eq!(missing_func_defs(DEFAULT_FRAG, &funcs).len(), 1);
+    let code = format!(
+        "{}\n{DEFAULT_FRAG}",
+        funcs.get("halve").unwrap().glsl.as_ref().unwrap()
+    );
+    assert!(missing_func_defs(&code, &funcs).is_empty());
+
+    // declared later than the function it calls, despite sorting first
+    let src = "@func zed(x) = sin(x);\n@func amp(x) = zed(x) * 2;\nC | amp(u) ;";
+    let rr = crate::parser::parse_rewrite_rules(src).unwrap();
+    let defs = missing_func_defs(DEFAULT_FRAG, &rr.funcs);
+    assert!(defs[0].starts_with("float zed(") && defs[1].starts_with("float amp("));
+}
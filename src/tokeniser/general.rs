@@ -3,17 +3,16 @@ use std::marker::PhantomData;
 #[derive(Clone, Copy, Debug)]
 pub struct SourceLoc {
     /// number of lines down
-    pub line: u16,
+    pub line: u32,
     /// number of chars across
-    pub char: u8,
+    pub char: u32,
 }
 
-#[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
 pub struct TokenSpan {
-    pub line: u16,
-    pub startchar: u8,
-    pub length: u8,
+    pub line: u32,
+    pub startchar: u32,
+    pub length: u32,
 }
 
 pub static WHITESPACE: [char; 3] = [' ', '\t', '\n'];
@@ -44,9 +43,9 @@ pub trait ExposesCharstream {
 /// a call to Token::recognise will consume a number of tokens, the last `length` of which belong
 /// to the token (in case of an error, this may represent a partial token)
 pub enum RecognitionResult<T, E> {
-    NextToken { token: T, length: u8 },
+    NextToken { token: T, length: u32 },
     Eof,
-    Err { error: E, length: u8 },
+    Err { error: E, length: u32 },
 }
 
 pub trait TokenRec
@@ -65,7 +64,7 @@ pub struct TokenStream<TokenKind, CharStream: Iterator<Item = char>> {
     current: Option<char>,
     cursor: SourceLoc,
     // NOTE: the token gets placed past the end of a token, so the cursor may have wrapped
-    prev_lastchar: u8,
+    prev_lastchar: u32,
     _p: PhantomData<TokenKind>,
 }
 
@@ -85,7 +84,7 @@ where
             _p: Default::default(),
         }
     }
-    fn length2span(&self, length: u8) -> TokenSpan {
+    fn length2span(&self, length: u32) -> TokenSpan {
         match self.cursor.char.checked_sub(length) {
             Some(startchar) => TokenSpan {
                 line: self.cursor.line,
@@ -113,7 +112,6 @@ where
     TokenKind: TokenRec,
 {
     fn advance(&mut self) {
-        // TODO: check & communicate max lines (~65K per source file) and max chars per line (255)
         match self.current {
             Some('\n') => {
                 self.prev_lastchar = self.cursor.char;
@@ -155,18 +153,26 @@ where
     }
 }
 
-#[allow(dead_code)]
-pub fn highlight_token(src: &str, span: TokenSpan, cursor: char) {
+/// the source line containing the span, with the span marked by `cursor` chars on the line below
+pub fn highlight_span(src: &str, span: TokenSpan, cursor: char) -> String {
+    let mut out = String::new();
     if let Some(line) = src.lines().nth(span.line as usize) {
-        println!("{line}");
-        for _ in 0..span.startchar {
-            print!(" ");
+        out.push_str(line);
+        out.push('\n');
+        // NOTE: keeping tabs so the markers still line up
+        for c in line.chars().take(span.startchar as usize) {
+            out.push(if c == '\t' { '\t' } else { ' ' });
         }
         for _ in 0..span.length {
-            print!("{cursor}");
+            out.push(cursor);
         }
-        println!();
     }
+    out
+}
+
+#[allow(dead_code)]
+pub fn highlight_token(src: &str, span: TokenSpan, cursor: char) {
+    println!("{}", highlight_span(src, span, cursor));
 }
 
 #[macro_export]
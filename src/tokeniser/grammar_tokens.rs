@@ -8,24 +8,49 @@ pub enum GTokenKind {
         #[allow(dead_code)]
         name: String,
     },
+    /// float literal, kept as written
+    Number {
+        text: String,
+    },
     /// (
     LPar,
     /// )
     RPar,
+    /// [
+    LBracket,
+    /// ]
+    RBracket,
     Comma,
     Bar,
     Colon,
+    /// starts a directive, e.g. `@func`
+    At,
+    Equals,
+    Plus,
+    /// only when not starting a number
+    Minus,
+    Star,
+    Slash,
 }
 
 impl GTokenKind {
     pub fn as_str(&self) -> &str {
         match self {
             GTokenKind::Ident { name } => name,
+            GTokenKind::Number { text } => text,
             GTokenKind::LPar => "(",
             GTokenKind::RPar => ")",
+            GTokenKind::LBracket => "[",
+            GTokenKind::RBracket => "]",
             GTokenKind::Comma => ",",
             GTokenKind::Bar => "|",
             GTokenKind::Colon => ";",
+            GTokenKind::At => "@",
+            GTokenKind::Equals => "=",
+            GTokenKind::Plus => "+",
+            GTokenKind::Minus => "-",
+            GTokenKind::Star => "*",
+            GTokenKind::Slash => "/",
         }
     }
 }
@@ -33,6 +58,63 @@ impl GTokenKind {
 #[derive(Debug)]
 pub enum GTokenError {
     UnexpectedChar,
+    MalformedNumber,
+}
+
+fn push_digits<CS: ExposesCharstream>(cs: &mut CS, text: &mut String) -> usize {
+    let mut n = 0;
+    while let Some(c) = cs.current() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        text.push(c);
+        cs.advance();
+        n += 1;
+    }
+    n
+}
+
+/// recognises numbers such as 2, -0.5, .25 or 1e-3, or a lone minus
+fn recognise_number<CS: ExposesCharstream>(
+    cs: &mut CS,
+) -> RecognitionResult<GTokenKind, GTokenError> {
+    let mut text = String::new();
+    if cs.current() == Some('-') {
+        text.push('-');
+        cs.advance();
+        if !matches!(cs.current(), Some(c) if c == '.' || c.is_ascii_digit()) {
+            return RecognitionResult::NextToken {
+                token: GTokenKind::Minus,
+                length: 1,
+            };
+        }
+    }
+    let mut digits = push_digits(cs, &mut text);
+    if cs.current() == Some('.') {
+        text.push('.');
+        cs.advance();
+        digits += push_digits(cs, &mut text);
+    }
+    let mut valid = digits > 0;
+    if valid && matches!(cs.current(), Some('e' | 'E')) {
+        text.push('e');
+        cs.advance();
+        if let Some(c @ ('-' | '+')) = cs.current() {
+            text.push(c);
+            cs.advance();
+        }
+        valid = push_digits(cs, &mut text) > 0;
+    }
+    match valid {
+        true => RecognitionResult::NextToken {
+            length: text.chars().count() as u32,
+            token: GTokenKind::Number { text },
+        },
+        false => RecognitionResult::Err {
+            error: GTokenError::MalformedNumber,
+            length: text.chars().count() as u32,
+        },
+    }
 }
 
 impl TokenRec for GTokenKind {
@@ -55,8 +137,16 @@ impl TokenRec for GTokenKind {
             Some(',') => recog_single_char_token!(cs, GTokenKind::Comma),
             Some('(') => recog_single_char_token!(cs, GTokenKind::LPar),
             Some(')') => recog_single_char_token!(cs, GTokenKind::RPar),
+            Some('[') => recog_single_char_token!(cs, GTokenKind::LBracket),
+            Some(']') => recog_single_char_token!(cs, GTokenKind::RBracket),
             Some('|') => recog_single_char_token!(cs, GTokenKind::Bar),
             Some(';') => recog_single_char_token!(cs, GTokenKind::Colon),
+            Some('@') => recog_single_char_token!(cs, GTokenKind::At),
+            Some('=') => recog_single_char_token!(cs, GTokenKind::Equals),
+            Some('+') => recog_single_char_token!(cs, GTokenKind::Plus),
+            Some('*') => recog_single_char_token!(cs, GTokenKind::Star),
+            Some('/') => recog_single_char_token!(cs, GTokenKind::Slash),
+            Some(c) if c == '-' || c == '.' || c.is_ascii_digit() => recognise_number(cs),
             Some(mut c) => {
                 let mut name = String::new();
                 while c == '_' || c.is_alphanumeric() {
@@ -76,7 +166,7 @@ impl TokenRec for GTokenKind {
                     };
                 }
                 RecognitionResult::NextToken {
-                    length: name.len() as u8,
+                    length: name.chars().count() as u32,
                     token: GTokenKind::Ident { name },
                 }
             }
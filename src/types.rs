@@ -0,0 +1,72 @@
+//! The GLSL types rules and functions can produce, and their values on the CPU.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Type {
+    Float,
+    Vec2,
+    Vec3,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "float" => Some(Self::Float),
+            "vec2" => Some(Self::Vec2),
+            "vec3" => Some(Self::Vec3),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Type::Float => "float",
+            Type::Vec2 => "vec2",
+            Type::Vec3 => "vec3",
+        }
+    }
+    /// number of components
+    pub fn width(&self) -> usize {
+        match self {
+            Type::Float => 1,
+            Type::Vec2 => 2,
+            Type::Vec3 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Float(f32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+}
+
+impl Value {
+    /// from 1 to 3 components
+    pub fn from_comps(comps: &[f32]) -> Self {
+        match comps {
+            [x] => Value::Float(*x),
+            [x, y] => Value::Vec2([*x, *y]),
+            [x, y, z] => Value::Vec3([*x, *y, *z]),
+            _ => panic!("No value type has {} components.", comps.len()),
+        }
+    }
+    pub fn nan(ty: Type) -> Self {
+        Self::from_comps(&[f32::NAN; 3][..ty.width()])
+    }
+    pub fn comps(&self) -> &[f32] {
+        match self {
+            Value::Float(x) => std::slice::from_ref(x),
+            Value::Vec2(xy) => xy,
+            Value::Vec3(xyz) => xyz,
+        }
+    }
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Float(_) => Type::Float,
+            Value::Vec2(_) => Type::Vec2,
+            Value::Vec3(_) => Type::Vec3,
+        }
+    }
+}
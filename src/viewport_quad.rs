@@ -1,7 +1,4 @@
-use egui_inspect::{
-    eframe::glow::{self, HasContext, Program, VertexArray},
-    logging::log::warn,
-};
+use egui_inspect::eframe::glow::{self, HasContext, Program, VertexArray};
 use std::sync::Arc;
 
 /// print on gl error
@@ -32,17 +29,13 @@ pub struct ViewportQuad {
 static LARGE_TRI_VERT_SHADER: &str = include_str!("../viewport_tri_vertex.glsl");
 
 impl ViewportQuad {
-    pub fn new(gl: &Arc<glow::Context>, fragment_shader_source: &str) -> Self {
+    /// draws nothing until given a fragment shader
+    pub fn new(gl: &Arc<glow::Context>) -> Self {
         unsafe {
             let va = gl
                 .create_vertex_array()
                 .expect("Cannot create vertex array");
-
-            let mut new = Self { va, prog: None };
-            if let Err(e) = new.set_frag_shader(gl, fragment_shader_source) {
-                warn!("Could not set frag shader: {e}")
-            }
-            new
+            Self { va, prog: None }
         }
     }
     // NOTE: adapted from https://github.com/grovesNL/glow/blob/main/examples/hello/src/main.rs